@@ -0,0 +1,119 @@
+// Segment aggregation on a synthetic 10k-activity corpus: the original
+// formatted String keys against packed integer keys, both on the same metric
+// grid so only the key format differs, plus the full builder pipeline. Run
+// with `cargo bench`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use heatmap_parse::grid::{Grid, SegmentCounts};
+use heatmap_parse::{HeatmapBuilder, HeatmapOptions};
+
+const ACTIVITIES: usize = 10_000;
+const POINTS_PER_ACTIVITY: usize = 500;
+const GRID_SIZE_M: f64 = 100.0;
+
+fn main() {
+    let tracks = synthetic_tracks();
+    let points: usize = tracks.iter().map(Vec::len).sum();
+    println!("{} activities, {} points", tracks.len(), points);
+
+    let (_, string_time) = time(|| aggregate_string_keys(&tracks));
+    let (_, packed_time) = time(|| aggregate_packed_keys(&tracks));
+
+    report("String keys", string_time);
+    report("packed keys", packed_time);
+    println!("speedup {:.1}x", string_time.as_secs_f64() / packed_time.as_secs_f64());
+
+    // End to end, including JSON parsing, jump filtering and simplification
+    let inputs: Vec<String> = tracks.iter().map(|track| format!("{:?}", track)).collect();
+    let (_, builder_time) = time(|| {
+        let mut builder = HeatmapBuilder::new(HeatmapOptions::default()).unwrap();
+        for input in &inputs {
+            builder.add_polyline(input);
+        }
+        builder.finish().max_frequency
+    });
+    report("HeatmapBuilder", builder_time);
+}
+
+fn time<T>(run: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let value = run();
+    (value, start.elapsed())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!("{:<16} {:>8.1} ms", name, elapsed.as_secs_f64() * 1000.0);
+}
+
+// Random walks around a handful of shared start points, so segments overlap
+fn synthetic_tracks() -> Vec<Vec<[f64; 2]>> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+
+    (0..ACTIVITIES)
+        .map(|i| {
+            let mut lat = 47.6 + (i % 20) as f64 * 0.01;
+            let mut lon = -122.3 + (i % 7) as f64 * 0.01;
+            (0..POINTS_PER_ACTIVITY)
+                .map(|_| {
+                    lat += (random() - 0.5) * 0.0008;
+                    lon += (random() - 0.5) * 0.0008;
+                    [(lat * 1e5).round() / 1e5, (lon * 1e5).round() / 1e5]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// The segment index as it was before integer keys: formatted cell pairs in a
+// SipHash map
+fn aggregate_string_keys(tracks: &[Vec<[f64; 2]>]) -> Vec<u32> {
+    let grid = Grid::new(GRID_SIZE_M);
+    let mut usage: HashMap<String, u32> = HashMap::new();
+    for track in tracks {
+        for window in track.windows(2) {
+            *usage.entry(string_key(&grid, window[0], window[1])).or_insert(0) += 1;
+        }
+    }
+    tracks
+        .iter()
+        .map(|track| frequency(track.windows(2).map(|w| usage[&string_key(&grid, w[0], w[1])])))
+        .collect()
+}
+
+fn aggregate_packed_keys(tracks: &[Vec<[f64; 2]>]) -> Vec<u32> {
+    let grid = Grid::new(GRID_SIZE_M);
+    let mut usage = SegmentCounts::default();
+    for track in tracks {
+        for window in track.windows(2) {
+            *usage.entry(grid.segment_key(window[0], window[1])).or_insert(0) += 1;
+        }
+    }
+    tracks
+        .iter()
+        .map(|track| frequency(track.windows(2).map(|w| usage[&grid.segment_key(w[0], w[1])])))
+        .collect()
+}
+
+fn frequency(usages: impl Iterator<Item = u32>) -> u32 {
+    let (total, count) = usages.fold((0, 0), |(total, count), usage| (total + usage, count + 1));
+    if count > 0 {
+        (total as f64 / count as f64).round() as u32
+    } else {
+        1
+    }
+}
+
+fn string_key(grid: &Grid, start: [f64; 2], end: [f64; 2]) -> String {
+    let (start, end) = (grid.cell(start), grid.cell(end));
+    let (first, second) = if start < end { (start, end) } else { (end, start) };
+    format!("{},{}-{},{}", first[0], first[1], second[0], second[1])
+}
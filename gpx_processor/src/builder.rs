@@ -0,0 +1,447 @@
+// Incremental heatmap aggregation. Inputs are parsed and cleaned as they are
+// added and only their simplified tracks and the segment usage counts are kept,
+// so an archive can be fed one activity at a time and snapshotted at any point.
+
+use std::borrow::Cow;
+
+use crate::endpoints::suggest_zones;
+use crate::grid::{segment_cells, track_segment_keys, Grid, SegmentCounts, SegmentKey, SegmentOwners};
+use crate::lod::add_levels;
+use crate::network::build_network;
+use crate::owners::{add_owner, visible_usage, Owners, UNKNOWN_OWNER};
+use crate::privacy::line_in_any_zone;
+use crate::{
+    clean_activity, process_file, process_polyline, HeatmapError, HeatmapInput, HeatmapNetwork, InputSource,
+    HeatmapOptions, HeatmapResult, HeatmapTrack, InputDiagnostics, InputFormat, OutputMode, Phase,
+    PrivacyZoneSuggestion, Result, RwgpsDocument, StravaActivity, Track,
+};
+
+// Called with the phase an input is about to enter; returning false stops processing
+pub(crate) type Observer<'a> = dyn FnMut(Phase, &InputDiagnostics) -> bool + 'a;
+
+pub struct HeatmapBuilder {
+    pub(crate) options: HeatmapOptions,
+    pub(crate) tracks: Vec<Track>,
+    pub(crate) segment_usage: SegmentCounts,
+    pub(crate) diagnostics: Vec<InputDiagnostics>,
+    // Start and end of every cleaned activity, before privacy zones and trimming
+    pub(crate) endpoints: Vec<[f64; 2]>,
+    // Only tracked when `min_owners` is above 1
+    pub(crate) segment_owners: SegmentOwners,
+    pub(crate) owners: Owners,
+}
+
+impl HeatmapBuilder {
+    pub fn new(options: HeatmapOptions) -> Result<Self> {
+        options.validate()?;
+        Ok(Self {
+            options,
+            tracks: Vec::new(),
+            segment_usage: SegmentCounts::default(),
+            diagnostics: Vec::new(),
+            endpoints: Vec::new(),
+            segment_owners: SegmentOwners::default(),
+            owners: Owners::default(),
+        })
+    }
+
+    pub fn options(&self) -> &HeatmapOptions {
+        &self.options
+    }
+
+    // Number of inputs added so far, including ones that produced no track
+    pub fn input_count(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    // Owner ID for the inputs added from now on, for `min_owners`. None goes
+    // back to inputs without an owner, which all count as one unknown owner
+    // unless they carry their own, like a Strava activity's athlete ID.
+    pub fn set_owner(&mut self, owner: Option<&str>) {
+        self.owners.set_current(owner);
+    }
+
+    // Add a file or polyline. The input's own owner, if any, takes the place of
+    // the one from `set_owner`.
+    pub fn add_input(&mut self, input: HeatmapInput<'_>) -> &InputDiagnostics {
+        self.add_input_with(input, &mut |_, _| true);
+        self.last_diagnostics()
+    }
+
+    // Add a GPX or FIT file
+    pub fn add_file(&mut self, bytes: &[u8]) -> &InputDiagnostics {
+        self.add_input(HeatmapInput::file(bytes))
+    }
+
+    // Add an encoded polyline or a JSON array of [lat, lon] pairs
+    pub fn add_polyline(&mut self, polyline: &str) -> &InputDiagnostics {
+        self.add_input(HeatmapInput::polyline(polyline))
+    }
+
+    // Activities without an owner set count as their athlete's, when present
+    pub fn add_strava_activity(&mut self, activity: StravaActivity) -> &InputDiagnostics {
+        let owner = self.input_owner(None, activity.owner().as_deref());
+        self.add(
+            InputFormat::Strava,
+            owner,
+            |_, diagnostics| activity.into_track(diagnostics).into_iter().map(|track| vec![track]).collect(),
+            &mut |_, _| true,
+        );
+        self.last_diagnostics()
+    }
+
+    // Trips without an owner set count as their user's, when present
+    pub fn add_rwgps_trip(&mut self, trip: RwgpsDocument) -> &InputDiagnostics {
+        let trip = trip.into_trip();
+        let owner = self.input_owner(None, trip.owner().as_deref());
+        self.add(
+            InputFormat::Ridewithgps,
+            owner,
+            |_, diagnostics| trip.into_track(diagnostics).into_iter().map(|track| vec![track]).collect(),
+            &mut |_, _| true,
+        );
+        self.last_diagnostics()
+    }
+
+    // Like add_input, but calls `observe` before each phase; returns false if it
+    // asked to stop, in which case the input is left out entirely
+    pub(crate) fn add_input_with(&mut self, input: HeatmapInput<'_>, observe: &mut Observer<'_>) -> bool {
+        let owner = self.input_owner(input.owner, None);
+        match input.source {
+            InputSource::File(bytes) => self.add(
+                InputFormat::Unknown,
+                owner,
+                |options, diagnostics| process_file(bytes, options, diagnostics),
+                observe,
+            ),
+            InputSource::Polyline(polyline) => self.add(
+                InputFormat::Unknown,
+                owner,
+                |_, diagnostics| vec![vec![process_polyline(polyline, diagnostics)]],
+                observe,
+            ),
+        }
+    }
+
+    // Record an input that couldn't be read at all, so it still shows up in the diagnostics
+    pub(crate) fn reject(&mut self, format: InputFormat, error: HeatmapError) -> &InputDiagnostics {
+        let mut diagnostics = self.next_diagnostics(format);
+        diagnostics.error(error);
+        self.push(None, UNKNOWN_OWNER, diagnostics)
+    }
+
+    // The heatmap for everything added so far
+    pub fn snapshot(&self) -> HeatmapResult {
+        let usage = self.visible_usage();
+        let tracks = match self.options.output_mode {
+            OutputMode::Tracks => self
+                .tracks
+                .iter()
+                .flat_map(|track| self.visible_pieces(track, &usage))
+                .map(|piece| {
+                    let frequency = self.track_frequency(&piece, &usage);
+                    heatmap_track(piece, frequency)
+                })
+                .collect(),
+            OutputMode::Segments => self.segment_lines(&usage),
+            OutputMode::SplitTracks => self
+                .tracks
+                .iter()
+                .flat_map(|track| self.visible_pieces(track, &usage))
+                .flat_map(|piece| self.split_track(&piece, &usage))
+                .collect(),
+            OutputMode::Network => {
+                let network = build_network(&usage, &self.grid());
+                return result(Vec::new(), Some(network), self.diagnostics.clone(), &self.options);
+            }
+        };
+
+        result(tracks, None, self.diagnostics.clone(), &self.options)
+    }
+
+    // The segment counts so far as a road network, whatever the output mode
+    pub fn network(&self) -> HeatmapNetwork {
+        build_network(&self.visible_usage(), &self.grid())
+    }
+
+    // Places where at least `min_endpoints` activity starts or ends lie within
+    // `radius_m` of each other, such as home or work, most used first
+    pub fn suggest_privacy_zones(&self, radius_m: f64, min_endpoints: usize) -> Result<Vec<PrivacyZoneSuggestion>> {
+        if !(radius_m.is_finite() && radius_m >= 1.0) {
+            return Err(HeatmapError::InvalidOptions("radius_m must be at least 1".to_string()));
+        }
+        Ok(suggest_zones(&self.endpoints, radius_m, min_endpoints.max(1)))
+    }
+
+    // Like snapshot, but hands over the stored tracks instead of copying them
+    pub fn finish(self) -> HeatmapResult {
+        if self.options.output_mode != OutputMode::Tracks || self.options.min_owners > 1 {
+            return self.snapshot();
+        }
+
+        let frequencies: Vec<u32> =
+            self.tracks.iter().map(|track| self.track_frequency(track, &self.segment_usage)).collect();
+        let tracks = self
+            .tracks
+            .into_iter()
+            .zip(frequencies)
+            .map(|(track, frequency)| heatmap_track(track, frequency))
+            .collect();
+
+        result(tracks, None, self.diagnostics, &self.options)
+    }
+
+    // Run one input through the pipeline: parse it into activities of raw
+    // tracks, clean them and count their segments
+    fn add(
+        &mut self,
+        format: InputFormat,
+        owner: u32,
+        parse: impl FnOnce(&HeatmapOptions, &mut InputDiagnostics) -> Vec<Vec<Track>>,
+        observe: &mut Observer<'_>,
+    ) -> bool {
+        let mut diagnostics = self.next_diagnostics(format);
+        if !observe(Phase::Parse, &diagnostics) {
+            return false;
+        }
+        let activities = parse(&self.options, &mut diagnostics);
+
+        if !observe(Phase::Filter, &diagnostics) {
+            return false;
+        }
+        let mut endpoints = Vec::new();
+        let tracks: Vec<Track> = activities
+            .into_iter()
+            .flat_map(|segments| clean_activity(segments, &self.options, &mut diagnostics, &mut endpoints))
+            .collect();
+
+        if !observe(Phase::Aggregate, &diagnostics) {
+            return false;
+        }
+        self.endpoints.extend(endpoints);
+        self.push(tracks, owner, diagnostics);
+        true
+    }
+
+    fn last_diagnostics(&self) -> &InputDiagnostics {
+        &self.diagnostics[self.diagnostics.len() - 1]
+    }
+
+    fn next_diagnostics(&self, format: InputFormat) -> InputDiagnostics {
+        InputDiagnostics::new(self.diagnostics.len(), format)
+    }
+
+    fn push(
+        &mut self,
+        tracks: impl IntoIterator<Item = Track>,
+        owner: u32,
+        diagnostics: InputDiagnostics,
+    ) -> &InputDiagnostics {
+        let min_owners = self.options.min_owners;
+        for track in tracks {
+            // Break each track into segments and count usage
+            for (_, key) in self.segment_keys(&track.coordinates) {
+                *self.segment_usage.entry(key).or_insert(0) += 1;
+                if min_owners > 1 {
+                    add_owner(&mut self.segment_owners, key, owner, min_owners);
+                }
+            }
+            self.tracks.push(track);
+        }
+
+        self.diagnostics.push(diagnostics);
+        self.last_diagnostics()
+    }
+
+    // Owner number for an input; owners only matter when min_owners is above 1
+    fn input_owner(&mut self, owner: Option<&str>, from_data: Option<&str>) -> u32 {
+        if self.options.min_owners <= 1 {
+            return UNKNOWN_OWNER;
+        }
+        self.owners.input_owner(owner, from_data)
+    }
+
+    fn grid(&self) -> Grid {
+        Grid::new(self.options.grid_size_m)
+    }
+
+    // A track's segments with the pair of points each lies on, leaving out
+    // those whose line between cell centers touches a privacy zone so segment
+    // and network output never reach into one. Every use of a track's segments goes through this,
+    // so the segments checked are the ones counted.
+    fn segment_keys(&self, coordinates: &[[f64; 2]]) -> Vec<(usize, SegmentKey)> {
+        let grid = self.grid();
+        let mut keys = track_segment_keys(coordinates, &grid, self.options.resample_spacing_m);
+        let zones = &self.options.privacy_zones;
+        if !zones.is_empty() {
+            keys.retain(|&(_, key)| {
+                let [start, end] = segment_cells(key);
+                !line_in_any_zone(zones, grid.center(start), grid.center(end))
+            });
+        }
+        keys
+    }
+
+    // Segment counts with the segments used by too few owners left out
+    fn visible_usage(&self) -> Cow<'_, SegmentCounts> {
+        visible_usage(&self.segment_usage, &self.segment_owners, self.options.min_owners)
+    }
+
+    // The runs of a track whose segments all have enough owners. Pairs of
+    // points without a segment of their own go with the pair before them.
+    fn visible_pieces(&self, track: &Track, usage: &SegmentCounts) -> Vec<Track> {
+        if self.options.min_owners <= 1 {
+            return vec![track.slice(0..track.len())];
+        }
+
+        let mut visible: Vec<Option<bool>> = vec![None; track.len() - 1];
+        for (pair, key) in self.segment_keys(&track.coordinates) {
+            let known = usage.contains_key(&key);
+            visible[pair] = Some(visible[pair].unwrap_or(true) && known);
+        }
+
+        let mut previous = visible.iter().flatten().next().copied().unwrap_or(false);
+        let mut pieces = Vec::new();
+        let mut start = None;
+        for (pair, visible) in visible.into_iter().enumerate() {
+            previous = visible.unwrap_or(previous);
+            match (previous, start) {
+                (true, None) => start = Some(pair),
+                (false, Some(first)) => {
+                    pieces.push(track.slice(first..pair + 1));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(first) = start {
+            pieces.push(track.slice(first..track.len()));
+        }
+        // Times, elevations and activity details would tie a piece back to
+        // the one person who recorded it
+        for piece in &mut pieces {
+            piece.elevations = None;
+            piece.times = None;
+            piece.activity = None;
+        }
+        pieces
+    }
+
+    // Calculate track frequency as the average frequency of its segments
+    fn track_frequency(&self, track: &Track, usage: &SegmentCounts) -> u32 {
+        let mut total_usage = 0;
+        let mut segment_count = 0;
+
+        for (_, key) in self.segment_keys(&track.coordinates) {
+            if let Some(&count) = usage.get(&key) {
+                total_usage += count;
+                segment_count += 1;
+            }
+        }
+
+        // Use average usage, with minimum of 1
+        if segment_count > 0 {
+            (total_usage as f64 / segment_count as f64).round() as u32
+        } else {
+            1
+        }
+    }
+
+    // Every distinct segment as a line between its cell centers, in key order
+    // so the output doesn't depend on hash order
+    fn segment_lines(&self, usage: &SegmentCounts) -> Vec<HeatmapTrack> {
+        let mut segments: Vec<(&SegmentKey, &u32)> = usage.iter().collect();
+        segments.sort_unstable();
+        let grid = self.grid();
+        segments
+            .into_iter()
+            .map(|(&key, &usage)| {
+                let [start, end] = segment_cells(key);
+                HeatmapTrack {
+                    coordinates: vec![grid.center(start), grid.center(end)],
+                    frequency: usage,
+                    elevations: None,
+                    times: None,
+                    activity: None,
+                    lod: Vec::new(),
+                }
+            })
+            .collect()
+    }
+
+    // Cut a track into runs of consecutive points whose segments share a
+    // frequency. Neighbouring pieces share their boundary point.
+    fn split_track(&self, track: &Track, usage: &SegmentCounts) -> Vec<HeatmapTrack> {
+        let frequencies = self.point_pair_frequencies(track, usage);
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        for end in 1..=frequencies.len() {
+            if end == frequencies.len() || frequencies[end] != frequencies[start] {
+                pieces.push(heatmap_track(track.slice(start..end + 1), frequencies[start]));
+                start = end;
+            }
+        }
+        pieces
+    }
+
+    // Frequency of each pair of consecutive points: the average usage of the
+    // segments on it. Pairs without a counted segment take their neighbour's.
+    fn point_pair_frequencies(&self, track: &Track, usage: &SegmentCounts) -> Vec<u32> {
+        let mut usages: Vec<(u32, u32)> = vec![(0, 0); track.len() - 1];
+        for (pair, key) in self.segment_keys(&track.coordinates) {
+            if let Some(&count) = usage.get(&key) {
+                usages[pair].0 += count;
+                usages[pair].1 += 1;
+            }
+        }
+        let frequencies: Vec<Option<u32>> = usages
+            .into_iter()
+            .map(|(total, count)| (count > 0).then(|| (total as f64 / count as f64).round() as u32))
+            .collect();
+
+        let mut previous = frequencies.iter().flatten().next().copied().unwrap_or(1);
+        frequencies
+            .into_iter()
+            .map(|frequency| {
+                previous = frequency.unwrap_or(previous);
+                previous
+            })
+            .collect()
+    }
+}
+
+fn heatmap_track(track: Track, frequency: u32) -> HeatmapTrack {
+    HeatmapTrack {
+        coordinates: track.coordinates,
+        frequency,
+        elevations: track.elevations,
+        times: track.times,
+        activity: track.activity,
+        lod: Vec::new(),
+    }
+}
+
+fn result(
+    tracks: Vec<HeatmapTrack>,
+    network: Option<HeatmapNetwork>,
+    diagnostics: Vec<InputDiagnostics>,
+    options: &HeatmapOptions,
+) -> HeatmapResult {
+    // Find the maximum frequency for normalization
+    let track_frequencies = tracks.iter().map(|track| track.frequency);
+    let edge_frequencies = network.iter().flat_map(|network| network.edges.iter().map(|edge| edge.frequency));
+    let max_frequency = track_frequencies.chain(edge_frequencies).max().unwrap_or(1);
+
+    let mut result = HeatmapResult {
+        tracks,
+        max_frequency,
+        network,
+        diagnostics,
+    };
+    add_levels(&mut result, options);
+    result
+}
@@ -0,0 +1,75 @@
+// Per-input diagnostics, so callers can tell why an activity is missing or shorter than expected
+
+use serde::Serialize;
+
+use crate::{Dwell, HeatmapError};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFormat {
+    Gpx,
+    Fit,
+    EncodedPolyline,
+    JsonCoordinates,
+    Strava,
+    Ridewithgps,
+    #[default]
+    Unknown,
+}
+
+// One entry per input, in input order. Point counts add up as:
+// points_read = dropped_invalid + dropped_jumps + dropped_spikes + dropped_dwell_points
+//     + hidden_points + trimmed_points + simplified_away + points kept
+// (plus points in pieces too short to form a track). Clipping at a privacy zone
+// adds a boundary point where a track crosses into or out of it; these aren't
+// counted, so with zones the right side can exceed points_read by that many.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct InputDiagnostics {
+    pub index: usize,
+    pub format: InputFormat,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    pub points_read: usize,
+    // Out of range, NaN or (0, 0) coordinates
+    pub dropped_invalid: usize,
+    // Places a track was split at a time or distance gap
+    pub gap_splits: usize,
+    // Removed by the unrealistic jump filter
+    pub dropped_jumps: usize,
+    // Removed as GPS spikes by the timestamp-based speed filter
+    pub dropped_spikes: usize,
+    // Places the speed filter split a track at an implausible jump
+    pub teleport_splits: usize,
+    // Places the track stayed in one spot, and the points removed collapsing or cutting them out
+    pub dwells_found: usize,
+    pub dropped_dwell_points: usize,
+    // Points inside privacy zones
+    pub hidden_points: usize,
+    // Points removed trimming the start and end
+    pub trimmed_points: usize,
+    pub simplified_away: usize,
+    pub tracks: usize,
+    // Each dwell, when `report_dwells` is set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dwells: Vec<Dwell>,
+}
+
+impl InputDiagnostics {
+    pub(crate) fn new(index: usize, format: InputFormat) -> Self {
+        Self {
+            index,
+            format,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn error(&mut self, error: HeatmapError) {
+        self.errors.push(error.to_string());
+    }
+
+    // Record a batch of raw points of which `valid` passed coordinate validation
+    pub(crate) fn read_points(&mut self, read: usize, valid: usize) {
+        self.points_read += read;
+        self.dropped_invalid += read - valid;
+    }
+}
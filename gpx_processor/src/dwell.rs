@@ -0,0 +1,190 @@
+// Dwell detection. A watch left running at a café or a traffic light keeps
+// logging fixes that wander around one spot, leaving a star-shaped scribble
+// that simplification keeps and aggregation turns into a fake hot spot. A
+// dwell is a run of at least three timestamped points that stays within
+// `dwell_radius_m` of its first point for `dwell_min_duration_s` or longer;
+// it's replaced by a single point at its centroid, or cut out of the track.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{distance, round, HeatmapOptions, InputDiagnostics, Track};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DwellAction {
+    // Replace the dwell's points with one point at their centroid
+    #[default]
+    Collapse,
+    // Split the track, leaving out the points inside the dwell
+    Break,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Dwell {
+    // [lat, lon] centroid of the dwell's points
+    pub coordinates: [f64; 2],
+    // Unix seconds of the first point
+    pub start_time: f64,
+    pub duration_s: f64,
+    pub points: usize,
+}
+
+pub(crate) fn remove_dwells(track: Track, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    let (Some(min_duration), Some(times)) = (options.dwell_min_duration_s, track.times.as_deref()) else {
+        return vec![track];
+    };
+    let coordinates = &track.coordinates;
+
+    // (first, last) index of each dwell
+    let mut dwells: Vec<(usize, usize)> = Vec::new();
+    let mut first = 0;
+    while first < track.len() {
+        let mut last = first;
+        while last + 1 < track.len() && distance(coordinates[first], coordinates[last + 1]) <= options.dwell_radius_m {
+            last += 1;
+        }
+        if last - first >= 2 && times[last] - times[first] >= min_duration {
+            dwells.push((first, last));
+            first = last + 1;
+        } else {
+            first += 1;
+        }
+    }
+    if dwells.is_empty() {
+        return vec![track];
+    }
+
+    diagnostics.dwells_found += dwells.len();
+    let centroids: Vec<[f64; 2]> = dwells
+        .iter()
+        .map(|&(first, last)| {
+            let points = &coordinates[first..=last];
+            let count = points.len() as f64;
+            let [lat, lon] = points.iter().fold([0.0, 0.0], |sum, point| [sum[0] + point[0], sum[1] + point[1]]);
+            let precision = options.coordinate_precision;
+            [round(lat / count, precision), round(lon / count, precision)]
+        })
+        .collect();
+    if options.report_dwells {
+        diagnostics.dwells.extend(dwells.iter().zip(&centroids).map(|(&(first, last), &coordinates)| Dwell {
+            coordinates,
+            start_time: times[first],
+            duration_s: times[last] - times[first],
+            points: last - first + 1,
+        }));
+    }
+
+    match options.dwell_action {
+        // Keep each dwell's first point, moved to the centroid, and its arrival time
+        DwellAction::Collapse => {
+            let mut keep = Vec::new();
+            let mut collapsed = Vec::new();
+            let mut next = 0;
+            for &(first, last) in &dwells {
+                keep.extend(next..first);
+                collapsed.push(keep.len());
+                keep.push(first);
+                diagnostics.dropped_dwell_points += last - first;
+                next = last + 1;
+            }
+            keep.extend(next..track.len());
+
+            let mut track = track.select(&keep);
+            for (position, centroid) in collapsed.into_iter().zip(centroids) {
+                track.coordinates[position] = centroid;
+            }
+            vec![track]
+        }
+        // Each piece ends where a dwell starts and the next begins where it ends
+        DwellAction::Break => {
+            let mut pieces = Vec::new();
+            let mut start = 0;
+            for &(first, last) in &dwells {
+                pieces.push(track.slice(start..first + 1));
+                diagnostics.dropped_dwell_points += last - first - 1;
+                start = last;
+            }
+            pieces.push(track.slice(start..track.len()));
+            pieces
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::METERS_PER_DEGREE;
+
+    const SPOT: [f64; 2] = [47.6, -122.3];
+
+    // [lat, lon] this many meters north and east of SPOT
+    fn offset(north: f64, east: f64) -> [f64; 2] {
+        [
+            SPOT[0] + north / METERS_PER_DEGREE,
+            SPOT[1] + east / (METERS_PER_DEGREE * SPOT[0].to_radians().cos()),
+        ]
+    }
+
+    // 20 s heading north at 30 m/s, 200 s wandering within a few meters of
+    // SPOT, then 20 s heading on north
+    fn track() -> Track {
+        let mut coordinates: Vec<[f64; 2]> = (0..20).map(|i| offset((i as f64 - 20.0) * 30.0, 0.0)).collect();
+        coordinates.extend((0..200).map(|i| offset(((i * 3) % 5) as f64 - 2.0, ((i * 7) % 5) as f64 - 2.0)));
+        coordinates.extend((1..=20).map(|i| offset(i as f64 * 30.0, 0.0)));
+        let times: Vec<f64> = (0..coordinates.len()).map(|i| i as f64).collect();
+        Track::from_points(&coordinates, None, Some(&times))
+    }
+
+    fn options(dwell_action: DwellAction) -> HeatmapOptions {
+        HeatmapOptions {
+            dwell_min_duration_s: Some(120.0),
+            dwell_action,
+            report_dwells: true,
+            ..HeatmapOptions::default()
+        }
+    }
+
+    #[test]
+    fn collapses_a_dwell_to_one_point() {
+        let mut diagnostics = InputDiagnostics::default();
+        let pieces = remove_dwells(track(), &options(DwellAction::Collapse), &mut diagnostics);
+        assert_eq!(pieces.len(), 1);
+        let piece = &pieces[0];
+        assert_eq!(piece.len(), 41);
+        assert!(distance(piece.coordinates[20], SPOT) < 5.0);
+        assert_eq!(piece.times.as_ref().unwrap()[20], 20.0);
+        assert_eq!(diagnostics.dwells_found, 1);
+        assert_eq!(diagnostics.dropped_dwell_points, 199);
+    }
+
+    #[test]
+    fn breaks_the_track_around_a_dwell() {
+        let mut diagnostics = InputDiagnostics::default();
+        let pieces = remove_dwells(track(), &options(DwellAction::Break), &mut diagnostics);
+        assert_eq!(pieces.iter().map(Track::len).collect::<Vec<_>>(), vec![21, 21]);
+        // The pieces meet the dwell at its first and last points
+        assert_eq!(pieces[0].times.as_ref().unwrap()[20], 20.0);
+        assert_eq!(pieces[1].times.as_ref().unwrap()[0], 219.0);
+        assert_eq!(diagnostics.dropped_dwell_points, 198);
+    }
+
+    #[test]
+    fn reports_each_dwell() {
+        let mut diagnostics = InputDiagnostics::default();
+        remove_dwells(track(), &options(DwellAction::Collapse), &mut diagnostics);
+        let [dwell] = diagnostics.dwells.as_slice() else {
+            panic!("expected one dwell, got {:?}", diagnostics.dwells);
+        };
+        assert_eq!((dwell.start_time, dwell.duration_s, dwell.points), (20.0, 199.0, 200));
+        assert!(distance(dwell.coordinates, SPOT) < 5.0);
+    }
+
+    #[test]
+    fn keeps_every_point_by_default() {
+        let mut diagnostics = InputDiagnostics::default();
+        let pieces = remove_dwells(track(), &HeatmapOptions::default(), &mut diagnostics);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].len(), 240);
+        assert_eq!(diagnostics.dwells_found, 0);
+    }
+}
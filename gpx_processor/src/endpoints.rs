@@ -0,0 +1,196 @@
+// Privacy zone suggestions. Activities mostly start and end at a handful of
+// places such as home and work, so clustering their endpoints finds the spots
+// worth hiding. Clusters come from DBSCAN with a metric radius: an endpoint
+// with at least min_endpoints endpoints within radius_m (counting itself) is a
+// core point, core points within radius_m of each other share a cluster, and
+// other endpoints join a cluster with a core point in reach.
+//
+// Endpoints are bucketed in a grid of half-radius cells, which are small
+// enough that any two endpoints in one cell are in reach. A cell holding
+// min_endpoints or more is all core points, so the crowded cells around home
+// don't need their points compared one by one.
+
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+
+use serde::Serialize;
+
+use crate::grid::{pack_cell, unpack_cell, CellHasher, Grid};
+use crate::{distance, PrivacyZone, METERS_PER_DEGREE};
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PrivacyZoneSuggestion {
+    // A circle covering every endpoint in the cluster with radius_m to spare,
+    // ready to pass in `privacy_zones`
+    pub zone: PrivacyZone,
+    // Activity starts and ends in the cluster
+    pub count: usize,
+}
+
+// One suggestion per cluster, most endpoints first
+pub(crate) fn suggest_zones(endpoints: &[[f64; 2]], radius_m: f64, min_endpoints: usize) -> Vec<PrivacyZoneSuggestion> {
+    let grid = Grid::new(radius_m / 2.0);
+    let mut cells: HashMap<u64, Vec<usize>, BuildHasherDefault<CellHasher>> = HashMap::default();
+    for (i, &point) in endpoints.iter().enumerate() {
+        cells.entry(pack_cell(grid.cell(point))).or_default().push(i);
+    }
+    let mut keys: Vec<u64> = cells.keys().copied().collect();
+    keys.sort_unstable();
+    let index: HashMap<u64, usize, BuildHasherDefault<CellHasher>> =
+        keys.iter().enumerate().map(|(i, &key)| (key, i)).collect();
+    let members: Vec<&[usize]> = keys.iter().map(|key| cells[key].as_slice()).collect();
+
+    // Cells that can hold endpoints in reach of a cell's endpoints: up to three
+    // rows away, and three columns either side of the cell's longitude in that row
+    let nearby: Vec<Vec<usize>> = keys
+        .iter()
+        .map(|&key| {
+            let center = grid.center(unpack_cell(key));
+            let mut found = Vec::new();
+            for row_offset in -3..=3 {
+                let offset = row_offset as f64 * radius_m / 2.0 / METERS_PER_DEGREE;
+                let [row, column] = grid.cell([center[0] + offset, center[1]]);
+                found.extend((column - 3..=column + 3).filter_map(|column| index.get(&pack_cell([row, column])).copied()));
+            }
+            found
+        })
+        .collect();
+    let in_reach = |a: usize, b: usize| distance(endpoints[a], endpoints[b]) <= radius_m;
+
+    let mut core = vec![false; endpoints.len()];
+    for (cell, points) in members.iter().enumerate() {
+        for &point in points.iter() {
+            core[point] = points.len() >= min_endpoints || {
+                let mut count = 0;
+                nearby[cell].iter().any(|&other| {
+                    count += members[other].iter().filter(|&&neighbour| in_reach(point, neighbour)).count();
+                    count >= min_endpoints
+                })
+            };
+        }
+    }
+
+    // Join cells whose core points reach each other
+    let mut parent: Vec<usize> = (0..keys.len()).collect();
+    fn root(parent: &mut [usize], mut cell: usize) -> usize {
+        while parent[cell] != cell {
+            parent[cell] = parent[parent[cell]];
+            cell = parent[cell];
+        }
+        cell
+    }
+    let core_points = |cell: usize| members[cell].iter().copied().filter(|&point| core[point]);
+    for (cell, others) in nearby.iter().enumerate() {
+        for &other in others {
+            if other <= cell || root(&mut parent, cell) == root(&mut parent, other) {
+                continue;
+            }
+            if core_points(cell).any(|a| core_points(other).any(|b| in_reach(a, b))) {
+                let (a, b) = (root(&mut parent, cell), root(&mut parent, other));
+                parent[a] = b;
+            }
+        }
+    }
+
+    // Core points belong to their cell's cluster, the rest to the first
+    // cluster with a core point in reach, if any
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (cell, points) in members.iter().enumerate() {
+        for &point in points.iter() {
+            let owner = if core[point] {
+                Some(cell)
+            } else {
+                nearby[cell]
+                    .iter()
+                    .copied()
+                    .find(|&other| core_points(other).any(|neighbour| in_reach(point, neighbour)))
+            };
+            if let Some(owner) = owner {
+                clusters.entry(root(&mut parent, owner)).or_default().push(point);
+            }
+        }
+    }
+    let mut clusters: Vec<Vec<usize>> = clusters.into_values().collect();
+    clusters.sort_unstable();
+
+    let mut suggestions: Vec<PrivacyZoneSuggestion> = clusters
+        .iter()
+        .map(|members| {
+            let count = members.len();
+            let [lat, lon] = members.iter().fold([0.0, 0.0], |sum, &i| {
+                [sum[0] + endpoints[i][0], sum[1] + endpoints[i][1]]
+            });
+            let center = [lat / count as f64, lon / count as f64];
+            let reach = members.iter().map(|&i| distance(center, endpoints[i])).fold(0.0, f64::max);
+            PrivacyZoneSuggestion {
+                zone: PrivacyZone::Circle {
+                    center,
+                    radius_m: reach + radius_m,
+                },
+                count,
+            }
+        })
+        .collect();
+    suggestions.sort_by_key(|suggestion| std::cmp::Reverse(suggestion.count));
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `count` endpoints spread up to ~30 m around a [lat, lon] center
+    fn around(center: [f64; 2], count: usize) -> Vec<[f64; 2]> {
+        (0..count)
+            .map(|i| {
+                let angle = i as f64 * 2.4;
+                let meters = 5.0 + (i % 5) as f64 * 6.0;
+                let x_scale = METERS_PER_DEGREE * center[0].to_radians().cos();
+                [center[0] + meters * angle.sin() / METERS_PER_DEGREE, center[1] + meters * angle.cos() / x_scale]
+            })
+            .collect()
+    }
+
+    const HOME: [f64; 2] = [47.6, -122.3];
+    const WORK: [f64; 2] = [47.62, -122.33];
+
+    fn center(suggestion: &PrivacyZoneSuggestion) -> [f64; 2] {
+        match suggestion.zone {
+            PrivacyZone::Circle { center, .. } => center,
+            PrivacyZone::Polygon(_) => panic!("expected a circle"),
+        }
+    }
+
+    #[test]
+    fn finds_two_separate_clusters() {
+        let endpoints = [around(HOME, 12), around(WORK, 8)].concat();
+        let suggestions = suggest_zones(&endpoints, 100.0, 3);
+        assert_eq!(suggestions.iter().map(|s| s.count).collect::<Vec<_>>(), [12, 8]);
+        for (suggestion, expected) in suggestions.iter().zip([HOME, WORK]) {
+            assert!(distance(center(suggestion), expected) < 10.0);
+            let PrivacyZone::Circle { radius_m, .. } = suggestion.zone else { unreachable!() };
+            assert!((100.0..=140.0).contains(&radius_m), "{radius_m}");
+        }
+    }
+
+    #[test]
+    fn ignores_endpoints_below_min_endpoints() {
+        // Lone endpoints kilometers apart, and a pair too small to count
+        let mut endpoints = around(HOME, 12);
+        endpoints.extend((1..=5).map(|i| [HOME[0] - i as f64 * 0.02, HOME[1] + i as f64 * 0.03]));
+        endpoints.extend(around(WORK, 2));
+        let suggestions = suggest_zones(&endpoints, 100.0, 3);
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].count, 12);
+        assert!(distance(center(&suggestions[0]), HOME) < 10.0);
+    }
+
+    #[test]
+    fn orders_suggestions_by_count() {
+        let gym = [47.58, -122.28];
+        let endpoints = [around(gym, 5), around(HOME, 20), around(WORK, 9)].concat();
+        let suggestions = suggest_zones(&endpoints, 100.0, 3);
+        assert_eq!(suggestions.iter().map(|s| s.count).collect::<Vec<_>>(), [20, 9, 5]);
+        assert!(distance(center(&suggestions[2]), gym) < 10.0);
+    }
+}
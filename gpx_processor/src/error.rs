@@ -0,0 +1,45 @@
+// Crate error type. Problems with a single input are recorded in that input's
+// diagnostics; these errors are returned when a whole call fails.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeatmapError {
+    // Input that is in a known format but could not be read
+    Parse(String),
+    // Input that is not in any supported format
+    Format(String),
+    // A safety limit was hit while reading input
+    LimitExceeded { limit: &'static str, value: usize },
+    // Output could not be converted for the caller
+    Serialization(String),
+    // Processing options out of range
+    InvalidOptions(String),
+    // Stopped by a cancellation token or progress callback
+    Cancelled,
+}
+
+pub type Result<T> = std::result::Result<T, HeatmapError>;
+
+impl fmt::Display for HeatmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeatmapError::Parse(message) => write!(f, "parse error: {}", message),
+            HeatmapError::Format(message) => write!(f, "unsupported format: {}", message),
+            HeatmapError::LimitExceeded { limit, value } => {
+                write!(f, "limit exceeded: {} ({})", limit, value)
+            }
+            HeatmapError::Serialization(message) => write!(f, "serialization error: {}", message),
+            HeatmapError::InvalidOptions(message) => write!(f, "invalid options: {}", message),
+            HeatmapError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for HeatmapError {}
+
+impl From<serde_json::Error> for HeatmapError {
+    fn from(error: serde_json::Error) -> Self {
+        HeatmapError::Parse(error.to_string())
+    }
+}
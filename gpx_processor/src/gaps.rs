@@ -0,0 +1,98 @@
+// Gap splitting. A recording paused for an hour, or a ride with a train in the
+// middle, has consecutive points far apart in time or space. Bridging them
+// would draw a straight line across the map, so the track is cut into pieces
+// there. Time gaps are split before any other filtering. Distance gaps are
+// split after it, so a single-point GPS spike is dropped by the speed filter
+// rather than cutting the track twice. Tracks without times have their own
+// threshold, off by default: sparse polylines legitimately have points
+// kilometers apart.
+
+use crate::{haversine_distance, HeatmapOptions, InputDiagnostics, Track};
+
+pub(crate) fn split_at_time_gaps(track: Track, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    let (Some(max), Some(times)) = (options.max_gap_s, track.times.as_deref()) else {
+        return vec![track];
+    };
+    let splits: Vec<usize> = (1..track.len()).filter(|&i| times[i] - times[i - 1] > max).collect();
+    split_at(track, &splits, diagnostics)
+}
+
+pub(crate) fn split_at_distance_gaps(
+    track: Track,
+    options: &HeatmapOptions,
+    diagnostics: &mut InputDiagnostics,
+) -> Vec<Track> {
+    let max_gap_m = if track.times.is_some() { options.max_gap_m } else { options.max_gap_m_untimed };
+    let Some(max) = max_gap_m else {
+        return vec![track];
+    };
+    let splits: Vec<usize> = (1..track.len())
+        .filter(|&i| {
+            let [previous, current] = [track.coordinates[i - 1], track.coordinates[i]];
+            haversine_distance(previous[0], previous[1], current[0], current[1]) * 1000.0 > max
+        })
+        .collect();
+    split_at(track, &splits, diagnostics)
+}
+
+// Cut the track so each split index starts a new piece
+fn split_at(track: Track, splits: &[usize], diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    if splits.is_empty() {
+        return vec![track];
+    }
+    diagnostics.gap_splits += splits.len();
+
+    let starts = std::iter::once(0).chain(splits.iter().copied());
+    let ends = splits.iter().copied().chain(std::iter::once(track.len()));
+    starts.zip(ends).map(|(start, end)| track.slice(start..end)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_gpx::gpx_1hz;
+    use crate::{process_files, process_polyline_strings, HeatmapOptions};
+
+    // A 1 Hz GPX track heading north at ~5 m/s, with point `spike` moved ~6 km east
+    fn gpx(points: usize, spike: Option<usize>) -> Vec<u8> {
+        gpx_1hz((0..points).map(|i| [47.6 + i as f64 * 0.000045, if Some(i) == spike { -122.22 } else { -122.3 }]))
+    }
+
+    #[test]
+    fn spike_is_filtered_not_split() {
+        let result = process_files(&[&gpx(600, Some(300))], &HeatmapOptions::default()).unwrap();
+        let diagnostics = &result.diagnostics[0];
+        assert_eq!(result.tracks.len(), 1);
+        assert_eq!(diagnostics.gap_splits, 0);
+        assert_eq!(diagnostics.dropped_spikes, 1);
+    }
+
+    #[test]
+    fn splits_timestamped_tracks_at_distance_gaps() {
+        let options = HeatmapOptions {
+            speed_filter: false,
+            ..HeatmapOptions::default()
+        };
+        let result = process_files(&[&gpx(600, Some(300))], &options).unwrap();
+        assert_eq!(result.diagnostics[0].gap_splits, 2);
+    }
+
+    #[test]
+    fn keeps_sparse_untimed_polylines() {
+        let polyline = "[[47.6,-122.3],[47.66,-122.3],[47.72,-122.3]]";
+        let result = process_polyline_strings(&[polyline], &HeatmapOptions::default()).unwrap();
+        assert_eq!(result.tracks.len(), 1);
+        assert_eq!(result.diagnostics[0].gap_splits, 0);
+    }
+
+    #[test]
+    fn splits_untimed_polylines_when_asked() {
+        let polyline = "[[47.6,-122.3],[47.6005,-122.3],[47.66,-122.3],[47.6605,-122.3]]";
+        let options = HeatmapOptions {
+            max_gap_m_untimed: Some(2000.0),
+            ..HeatmapOptions::default()
+        };
+        let result = process_polyline_strings(&[polyline], &options).unwrap();
+        assert_eq!(result.tracks.len(), 2);
+        assert_eq!(result.diagnostics[0].gap_splits, 1);
+    }
+}
@@ -0,0 +1,91 @@
+// GeoJSON output for map libraries and GIS tools
+
+use serde_json::{json, Map, Value};
+
+use crate::{HeatmapResult, HeatmapTrack, NetworkEdge, NetworkNode};
+
+impl HeatmapResult {
+    // A FeatureCollection with one LineString per track. GeoJSON positions are
+    // [lon, lat], the reverse of the [lat, lon] pairs used everywhere else.
+    // In the network output mode, edges become LineStrings with `from` and `to`
+    // node indices, and nodes become Points.
+    pub fn to_geojson(&self) -> Value {
+        let mut features: Vec<Value> = self.tracks.iter().map(track_feature).collect();
+        if let Some(network) = &self.network {
+            features.extend(network.edges.iter().map(edge_feature));
+            features.extend(network.nodes.iter().enumerate().map(node_feature));
+        }
+
+        json!({
+            "type": "FeatureCollection",
+            "max_frequency": self.max_frequency,
+            "features": features,
+        })
+    }
+}
+
+fn track_feature(track: &HeatmapTrack) -> Value {
+    let coordinates: Vec<Value> = track
+        .coordinates
+        .iter()
+        .enumerate()
+        .map(|(i, [lat, lon])| match &track.elevations {
+            Some(elevations) => json!([lon, lat, elevations[i]]),
+            None => json!([lon, lat]),
+        })
+        .collect();
+
+    let mut properties = Map::new();
+    properties.insert("frequency".to_string(), json!(track.frequency));
+    if let Some(activity) = &track.activity {
+        if let Value::Object(activity) = json!(activity) {
+            properties.extend(activity);
+        }
+    }
+    if let Some(times) = &track.times {
+        properties.insert("times".to_string(), json!(times));
+    }
+
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": coordinates,
+        },
+        "properties": properties,
+    })
+}
+
+fn edge_feature(edge: &NetworkEdge) -> Value {
+    let coordinates: Vec<Value> = edge.coordinates.iter().map(|[lat, lon]| json!([lon, lat])).collect();
+
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": coordinates,
+        },
+        "properties": {
+            "frequency": edge.frequency,
+            "from": edge.from,
+            "to": edge.to,
+        },
+    })
+}
+
+fn node_feature((index, node): (usize, &NetworkNode)) -> Value {
+    let [lat, lon] = node.coordinates;
+
+    json!({
+        "type": "Feature",
+        "id": index,
+        "geometry": {
+            "type": "Point",
+            "coordinates": [lon, lat],
+        },
+        "properties": {
+            "frequency": node.frequency,
+            "degree": node.degree,
+        },
+    })
+}
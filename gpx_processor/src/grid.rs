@@ -0,0 +1,280 @@
+// Grid cells used to match overlapping segments. The grid is metric: rows are
+// grid_size_m tall, and rows are grouped into bands about a degree of latitude
+// tall whose columns are grid_size_m wide at the band's middle latitude (a
+// local equirectangular scale). Cells are roughly square on the ground from the
+// equator to the far north, and columns line up within a band, so a straight
+// north-south road stays in one column instead of zigzagging from row to row. A cell packs into a u64 and
+// a segment, as an unordered pair of cells, into a u128. These are hashed with
+// a cheap multiplicative hasher instead of SipHash, since the keys aren't
+// attacker-chosen and hashing them is most of the aggregation cost.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::{haversine_distance, METERS_PER_DEGREE};
+
+// Two packed cells, smaller one (by row, then column) first
+pub type SegmentKey = u128;
+
+pub type SegmentCounts = HashMap<SegmentKey, u32, BuildHasherDefault<CellHasher>>;
+
+// Distinct owner numbers per segment
+pub type SegmentOwners = HashMap<SegmentKey, Vec<u32>, BuildHasherDefault<CellHasher>>;
+
+// Bands closer to the poles than this use this band's column width, so columns
+// don't shrink to nothing
+const MIN_COS_LATITUDE: f64 = 0.01;
+
+// Height of a band of rows sharing one column width
+const BAND_DEGREES: f64 = 1.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Grid {
+    // Row height in degrees of latitude
+    row_height: f64,
+    band_rows: i32,
+    size_m: f64,
+}
+
+impl Grid {
+    // Callers keep size_m large enough for cell indices to fit in 32 bits
+    // (see HeatmapOptions::validate)
+    pub fn new(size_m: f64) -> Self {
+        let row_height = size_m / METERS_PER_DEGREE;
+        Self {
+            row_height,
+            band_rows: ((BAND_DEGREES / row_height).round() as i32).max(1),
+            size_m,
+        }
+    }
+
+    // [row, column] of the cell containing a [lat, lon] point
+    pub fn cell(&self, point: [f64; 2]) -> [i32; 2] {
+        let row = (point[0] / self.row_height).round() as i32;
+        [row, (point[1] / self.column_width(row)).round() as i32]
+    }
+
+    // [lat, lon] of a cell's center
+    pub fn center(&self, cell: [i32; 2]) -> [f64; 2] {
+        [cell[0] as f64 * self.row_height, cell[1] as f64 * self.column_width(cell[0])]
+    }
+
+    // Direction-independent key for the segment between two points
+    pub fn segment_key(&self, start: [f64; 2], end: [f64; 2]) -> SegmentKey {
+        cell_pair_key(self.cell(start), self.cell(end))
+    }
+
+    // Column width in degrees of longitude for a row, taken at the middle of
+    // the row's band
+    fn column_width(&self, row: i32) -> f64 {
+        let band = row.div_euclid(self.band_rows) as f64;
+        let latitude = ((band + 0.5) * self.band_rows as f64 * self.row_height).to_radians();
+        self.size_m / (METERS_PER_DEGREE * latitude.cos().max(MIN_COS_LATITUDE))
+    }
+}
+
+pub fn pack_cell(cell: [i32; 2]) -> u64 {
+    ((cell[0] as u32 as u64) << 32) | cell[1] as u32 as u64
+}
+
+pub fn unpack_cell(packed: u64) -> [i32; 2] {
+    [(packed >> 32) as u32 as i32, packed as u32 as i32]
+}
+
+// The two cells of a segment key
+pub fn segment_cells(key: SegmentKey) -> [[i32; 2]; 2] {
+    [unpack_cell((key >> 64) as u64), unpack_cell(key as u64)]
+}
+
+pub fn cell_pair_key(start: [i32; 2], end: [i32; 2]) -> SegmentKey {
+    let (first, second) = if start < end { (start, end) } else { (end, start) };
+    ((pack_cell(first) as u128) << 64) | pack_cell(second) as u128
+}
+
+// The segment keys a track contributes, each with the index of the pair of
+// points (i, i + 1) it lies on. With a resample spacing the track is walked at
+// that interval in meters and each step into a new cell is a segment, on the
+// pair where the step lands, so recordings of the same road match whatever
+// their sampling rate. Without one, every pair of consecutive points is a segment.
+pub fn track_segment_keys(
+    coordinates: &[[f64; 2]],
+    grid: &Grid,
+    resample_spacing_m: Option<f64>,
+) -> Vec<(usize, SegmentKey)> {
+    let Some(spacing) = resample_spacing_m else {
+        return coordinates
+            .windows(2)
+            .map(|window| grid.segment_key(window[0], window[1]))
+            .enumerate()
+            .collect();
+    };
+
+    let cells = resampled_cells(coordinates, grid, spacing);
+    cells.windows(2).map(|window| (window[1].0, cell_pair_key(window[0].1, window[1].1))).collect()
+}
+
+// Cells visited when sampling the track every `spacing` meters, without
+// consecutive repeats, each with the pair of points it was entered on. The
+// first and last points are always sampled.
+fn resampled_cells(coordinates: &[[f64; 2]], grid: &Grid, spacing: f64) -> Vec<(usize, [i32; 2])> {
+    let mut cells: Vec<(usize, [i32; 2])> = Vec::new();
+    let mut visit = |pair: usize, point: [f64; 2]| {
+        let next = grid.cell(point);
+        if cells.last().map(|&(_, cell)| cell) != Some(next) {
+            cells.push((pair, next));
+        }
+    };
+
+    let Some(&first) = coordinates.first() else {
+        return cells;
+    };
+    visit(0, first);
+
+    // Distance walked since the last sample
+    let mut since_sample = 0.0;
+    for (pair, window) in coordinates.windows(2).enumerate() {
+        let [start, end] = [window[0], window[1]];
+        let length = haversine_distance(start[0], start[1], end[0], end[1]) * 1000.0;
+
+        let mut along = spacing - since_sample;
+        while along < length {
+            let t = along / length;
+            visit(pair, [start[0] + (end[0] - start[0]) * t, start[1] + (end[1] - start[1]) * t]);
+            along += spacing;
+        }
+        since_sample = length - (along - spacing);
+    }
+
+    if let Some(&last) = coordinates.last() {
+        visit(coordinates.len().saturating_sub(2), last);
+    }
+    cells
+}
+
+// FxHash-style: rotate, xor and multiply per word
+#[derive(Default, Clone, Copy)]
+pub struct CellHasher {
+    hash: u64,
+}
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl CellHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for CellHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.add(value);
+    }
+
+    fn write_u128(&mut self, value: u128) {
+        self.add(value as u64);
+        self.add((value >> 64) as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Random walks with point spacing from a few meters to a few hundred
+    fn random_tracks() -> Vec<Vec<[f64; 2]>> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..200)
+            .map(|_| {
+                let [mut lat, mut lon] = [47.6, -122.3];
+                let step = 0.00005 + random() * 0.003;
+                (0..50)
+                    .map(|_| {
+                        lat += (random() - 0.5) * step;
+                        lon += (random() - 0.5) * step;
+                        [lat, lon]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keys_lie_on_their_pair() {
+        let grid = Grid::new(100.0);
+        for track in random_tracks() {
+            let keys = track_segment_keys(&track, &grid, Some(25.0));
+            assert!(keys.windows(2).all(|window| window[0].0 <= window[1].0));
+            for (pair, key) in keys {
+                assert!(pair < track.len() - 1);
+                // Both cells are within reach of the pair's segment: the one
+                // left within a resample step, the one entered on it
+                let [start, end] = [track[pair], track[pair + 1]];
+                let near = |cell: [i32; 2]| {
+                    let center = grid.center(cell);
+                    (0..=20).any(|i| {
+                        let t = i as f64 / 20.0;
+                        let point = [start[0] + (end[0] - start[0]) * t, start[1] + (end[1] - start[1]) * t];
+                        haversine_distance(center[0], center[1], point[0], point[1]) * 1000.0 < 100.0 + 25.0
+                    })
+                };
+                assert!(segment_cells(key).iter().any(|&cell| near(cell)));
+            }
+        }
+    }
+
+    #[test]
+    fn north_south_line_stays_in_one_column() {
+        let grid = Grid::new(100.0);
+        for start in [[47.1, -122.3], [-33.9, 151.2], [69.2, 18.9], [1.3, 103.8]] {
+            let cells: Vec<[i32; 2]> = (0..500)
+                .map(|i| grid.cell([start[0] + i as f64 * 0.001, start[1]]))
+                .collect();
+            assert!(cells.iter().all(|cell| cell[1] == cells[0][1]), "{start:?}");
+            let lon = grid.center(cells[0])[1];
+            assert!(cells.iter().all(|&cell| grid.center(cell)[1] == lon));
+        }
+    }
+
+    #[test]
+    fn cells_stay_roughly_square() {
+        for size_m in [25.0, 100.0, 1000.0] {
+            let grid = Grid::new(size_m);
+            for lat in [0.0, 35.0, 60.0, 70.0, -45.0] {
+                let cell = grid.cell([lat, 10.0]);
+                let [center, next] = [grid.center(cell), grid.center([cell[0], cell[1] + 1])];
+                let meters = haversine_distance(center[0], center[1], next[0], next[1]) * 1000.0;
+                assert!((meters - size_m).abs() < size_m * 0.05, "{size_m} at {lat}: {meters}");
+            }
+        }
+    }
+
+    #[test]
+    fn keys_match_unindexed_walk_without_resampling() {
+        let grid = Grid::new(100.0);
+        let track = &random_tracks()[0];
+        let keys = track_segment_keys(track, &grid, None);
+        assert_eq!(keys.len(), track.len() - 1);
+        for (i, (pair, key)) in keys.into_iter().enumerate() {
+            assert_eq!(pair, i);
+            assert_eq!(key, grid.segment_key(track[i], track[i + 1]));
+        }
+    }
+}
@@ -1,31 +1,322 @@
-use wasm_bindgen::prelude::*;
 use gpx::read;
 use std::io::Cursor;
 use serde::Serialize;
 use std::collections::HashMap;
 
+mod builder;
+mod diagnostics;
+mod dwell;
+mod endpoints;
+mod error;
+mod gaps;
+mod geojson;
+pub mod grid;
+mod lod;
+mod motion;
+mod network;
+mod options;
+mod owners;
+mod persist;
+mod privacy;
+mod progress;
+mod simplify;
+mod smooth;
+mod ridewithgps;
+mod strava;
+#[cfg(test)]
+mod test_gpx;
+mod trim;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use builder::HeatmapBuilder;
+pub use diagnostics::{InputDiagnostics, InputFormat};
+pub use dwell::{Dwell, DwellAction};
+pub use endpoints::PrivacyZoneSuggestion;
+pub use error::{HeatmapError, Result};
+pub use lod::LevelOfDetail;
+pub use network::{HeatmapNetwork, NetworkEdge, NetworkNode};
+pub use options::{HeatmapOptions, OutputMode};
+pub use privacy::PrivacyZone;
+pub use progress::{CancellationToken, Phase, Progress};
+pub use ridewithgps::{RwgpsDocument, RwgpsTrackPoint, RwgpsTrip};
+pub use simplify::SimplifyMode;
+pub use smooth::SmoothingMode;
+pub use strava::{StravaActivity, StravaAthlete, StravaMap, StravaStreams};
+
+use dwell::remove_dwells;
+use gaps::{split_at_distance_gaps, split_at_time_gaps};
+use motion::filter_motion;
+use privacy::clip_to_zones;
+use simplify::simplify;
+use smooth::smooth;
+use trim::{trim_ends, trim_seed};
+
 // Define the main data structures
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Debug)]
 pub struct HeatmapTrack {
+    // [lat, lon] pairs
+    pub coordinates: Vec<[f64; 2]>,
+    pub frequency: u32,
+    // Per-point elevation (meters) and time (Unix seconds), when the source provides them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elevations: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub times: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity: Option<ActivityMetadata>,
+    // Simplified geometry per zoom level, when `lod_zooms` is set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lod: Vec<LevelOfDetail>,
+}
+
+// Metadata about the activity a track came from (Strava, RideWithGPS, ...)
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct ActivityMetadata {
+    pub source: ActivitySource,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sport_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_date: Option<String>,
+    // Meters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+}
+
+// Which API an activity was imported from
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivitySource {
+    #[default]
+    Strava,
+    Ridewithgps,
+}
+
+// A track on its way through the pipeline. Elevations and times, when present,
+// always have the same length as the coordinates.
+struct Track {
     coordinates: Vec<[f64; 2]>,
-    frequency: u32,
+    elevations: Option<Vec<f64>>,
+    times: Option<Vec<f64>>,
+    activity: Option<ActivityMetadata>,
 }
 
-#[derive(Serialize)]
+impl Track {
+    // Build a track from raw per-point data, dropping invalid coordinates.
+    // Elevation or time series that don't line up with the coordinates are ignored.
+    fn from_points(
+        coordinates: &[[f64; 2]],
+        elevations: Option<&[f64]>,
+        times: Option<&[f64]>,
+    ) -> Self {
+        let elevations = elevations.filter(|e| e.len() == coordinates.len());
+        let times = times.filter(|t| t.len() == coordinates.len());
+
+        let keep: Vec<usize> = (0..coordinates.len())
+            .filter(|&i| is_valid_coordinate(coordinates[i][0], coordinates[i][1]))
+            .collect();
+
+        Self {
+            coordinates: keep.iter().map(|&i| coordinates[i]).collect(),
+            elevations: elevations.map(|e| keep.iter().map(|&i| e[i]).collect()),
+            times: times.map(|t| keep.iter().map(|&i| t[i]).collect()),
+            activity: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.coordinates.len()
+    }
+
+    // Copy of the points in `range`
+    fn slice(&self, range: std::ops::Range<usize>) -> Self {
+        Self {
+            coordinates: self.coordinates[range.clone()].to_vec(),
+            elevations: self.elevations.as_ref().map(|e| e[range.clone()].to_vec()),
+            times: self.times.as_ref().map(|t| t[range].to_vec()),
+            activity: self.activity.clone(),
+        }
+    }
+
+    // Copy of the points at the given (ascending) indices
+    fn pick(&self, indices: &[usize]) -> Self {
+        let pick = |values: &Vec<f64>| indices.iter().map(|&i| values[i]).collect();
+        Self {
+            coordinates: indices.iter().map(|&i| self.coordinates[i]).collect(),
+            elevations: self.elevations.as_ref().map(pick),
+            times: self.times.as_ref().map(pick),
+            activity: self.activity.clone(),
+        }
+    }
+
+    // Keep only the points at the given (ascending) indices
+    fn select(self, indices: &[usize]) -> Self {
+        let pick = |values: Vec<f64>| indices.iter().map(|&i| values[i]).collect();
+        Self {
+            coordinates: indices.iter().map(|&i| self.coordinates[i]).collect(),
+            elevations: self.elevations.map(pick),
+            times: self.times.map(pick),
+            activity: self.activity,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
 pub struct HeatmapResult {
-    tracks: Vec<HeatmapTrack>,
-    max_frequency: u32,
+    pub tracks: Vec<HeatmapTrack>,
+    pub max_frequency: u32,
+    // Merged road network, in the `network` output mode (tracks is empty then)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<HeatmapNetwork>,
+    // One entry per input, in input order
+    pub diagnostics: Vec<InputDiagnostics>,
+}
+
+// A single input for `process_inputs`
+#[derive(Clone, Copy, Debug)]
+pub struct HeatmapInput<'a> {
+    pub source: InputSource<'a>,
+    // Owner ID for `min_owners`, e.g. a club member; inputs without one all
+    // count as a single unknown owner
+    pub owner: Option<&'a str>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum InputSource<'a> {
+    // GPX or FIT file contents
+    File(&'a [u8]),
+    // Encoded polyline or JSON array of [lat, lon] pairs
+    Polyline(&'a str),
+}
+
+impl<'a> HeatmapInput<'a> {
+    pub fn file(bytes: &'a [u8]) -> Self {
+        Self {
+            source: InputSource::File(bytes),
+            owner: None,
+        }
+    }
+
+    pub fn polyline(polyline: &'a str) -> Self {
+        Self {
+            source: InputSource::Polyline(polyline),
+            owner: None,
+        }
+    }
+
+    pub fn with_owner(self, owner: Option<&'a str>) -> Self {
+        Self { owner, ..self }
+    }
+
+    // Size in bytes, for progress reporting
+    fn size(&self) -> usize {
+        match self.source {
+            InputSource::File(bytes) => bytes.len(),
+            InputSource::Polyline(polyline) => polyline.len(),
+        }
+    }
+}
+
+// Process GPX and FIT files given as raw bytes
+pub fn process_files(files: &[&[u8]], options: &HeatmapOptions) -> Result<HeatmapResult> {
+    let inputs: Vec<HeatmapInput> = files.iter().map(|bytes| HeatmapInput::file(bytes)).collect();
+    process_inputs(&inputs, options)
+}
+
+// Process a mix of files and polylines into a single heatmap
+pub fn process_inputs(inputs: &[HeatmapInput<'_>], options: &HeatmapOptions) -> Result<HeatmapResult> {
+    let mut builder = HeatmapBuilder::new(options.clone())?;
+    for input in inputs {
+        builder.add_input(*input);
+    }
+    Ok(builder.finish())
+}
+
+// Like process_files, reporting progress as it goes. Returning false from
+// `on_progress`, or cancelling `cancel`, stops with HeatmapError::Cancelled.
+pub fn process_files_with_progress(
+    files: &[&[u8]],
+    options: &HeatmapOptions,
+    cancel: &CancellationToken,
+    on_progress: impl FnMut(&Progress) -> bool,
+) -> Result<HeatmapResult> {
+    let inputs: Vec<HeatmapInput> = files.iter().map(|bytes| HeatmapInput::file(bytes)).collect();
+    process_inputs_with_progress(&inputs, options, cancel, on_progress)
+}
+
+// Like process_inputs, reporting progress and stopping the same way
+pub fn process_inputs_with_progress(
+    inputs: &[HeatmapInput<'_>],
+    options: &HeatmapOptions,
+    cancel: &CancellationToken,
+    mut on_progress: impl FnMut(&Progress) -> bool,
+) -> Result<HeatmapResult> {
+    let mut builder = HeatmapBuilder::new(options.clone())?;
+    let mut progress = Progress {
+        files_total: inputs.len(),
+        bytes_total: inputs.iter().map(HeatmapInput::size).sum(),
+        ..Progress::default()
+    };
+
+    for input in inputs {
+        let points_before = progress.points;
+        let completed = builder.add_input_with(*input, &mut |phase, diagnostics| {
+            progress.phase = phase;
+            progress.points = points_before + diagnostics.points_read;
+            !cancel.is_cancelled() && on_progress(&progress)
+        });
+        if !completed {
+            return Err(HeatmapError::Cancelled);
+        }
+        progress.files_done += 1;
+        progress.bytes_done += input.size();
+    }
+
+    // Final report so a progress bar reaches 100%; there's nothing left to cancel
+    on_progress(&progress);
+    Ok(builder.finish())
 }
 
-// Add a console log function for debugging
-#[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    fn log(s: &str);
+// Process polyline strings, each either an encoded polyline or a JSON array of [lat, lon] pairs
+pub fn process_polyline_strings(polylines: &[&str], options: &HeatmapOptions) -> Result<HeatmapResult> {
+    process_polyline_inputs(polylines.iter().map(|p| (Some(*p), None)), options)
+}
+
+// Process Strava activities, carrying each activity's metadata into its track.
+// Activities with attached streams use the full-resolution latlng/time/altitude data,
+// the rest fall back to their (summary) polyline.
+pub fn process_strava(activities: Vec<StravaActivity>, options: &HeatmapOptions) -> Result<HeatmapResult> {
+    let mut builder = HeatmapBuilder::new(options.clone())?;
+    for activity in activities {
+        builder.add_strava_activity(activity);
+    }
+    Ok(builder.finish())
+}
+
+// Process RideWithGPS trips or routes (bare or wrapped in {"trip": ...} / {"route": ...}),
+// keeping elevation, time and trip metadata from their track_points
+pub fn process_rwgps(trips: Vec<RwgpsDocument>, options: &HeatmapOptions) -> Result<HeatmapResult> {
+    let mut builder = HeatmapBuilder::new(options.clone())?;
+    for trip in trips {
+        builder.add_rwgps_trip(trip);
+    }
+    Ok(builder.finish())
 }
 
 // Function to decode Google polyline format
 pub fn decode_polyline(encoded: &str) -> Vec<[f64; 2]> {
+    decode_polyline_raw(encoded)
+        .into_iter()
+        .filter(|c| is_valid_coordinate(c[0], c[1]))
+        .collect()
+}
+
+// Decode without coordinate validation, so callers can count what gets dropped
+fn decode_polyline_raw(encoded: &str) -> Vec<[f64; 2]> {
     let mut coords = Vec::new();
     let mut lat = 0i32;
     let mut lng = 0i32;
@@ -73,284 +364,274 @@ pub fn decode_polyline(encoded: &str) -> Vec<[f64; 2]> {
         let lat_f64 = lat as f64 * 1e-5;
         let lng_f64 = lng as f64 * 1e-5;
         
-        if is_valid_coordinate(lat_f64, lng_f64) {
-            coords.push([lat_f64, lng_f64]);
-        }
+        coords.push([lat_f64, lng_f64]);
     }
     
     coords
 }
 
-// Wasm-bindgen export for polyline decoding
-#[wasm_bindgen]
-pub fn decode_polyline_string(encoded: &str) -> JsValue {
-    let coords = decode_polyline(encoded);
-    serde_wasm_bindgen::to_value(&coords).unwrap()
+// Process polyline strings - handles both encoded polylines and JSON coordinate arrays
+fn process_polyline(polyline_str: &str, diagnostics: &mut InputDiagnostics) -> Track {
+    // First try to parse as JSON (RideWithGPS format), otherwise treat it as
+    // an encoded polyline (Strava format)
+    let coords = match serde_json::from_str::<Vec<[f64; 2]>>(polyline_str) {
+        Ok(json_coords) => {
+            diagnostics.format = InputFormat::JsonCoordinates;
+            json_coords
+        }
+        Err(_) => {
+            diagnostics.format = InputFormat::EncodedPolyline;
+            decode_polyline_raw(polyline_str)
+        }
+    };
+
+    let track = Track::from_points(&coords, None, None);
+    diagnostics.read_points(coords.len(), track.len());
+    track
 }
 
-// Process polyline strings - handles both encoded polylines and JSON coordinate arrays
-fn process_polyline(polyline_str: &str) -> Vec<[f64; 2]> {
-    // First try to parse as JSON (RideWithGPS format)
-    if let Ok(json_coords) = serde_json::from_str::<Vec<[f64; 2]>>(polyline_str) {
-        // It's a JSON array of coordinates
-        return if !json_coords.is_empty() {
-            filter_unrealistic_jumps(&json_coords)
-        } else {
-            Vec::new()
+// Process polyline inputs with their owners in order; a `None` polyline marks
+// an entry that wasn't a string
+fn process_polyline_inputs<'a>(
+    polylines: impl IntoIterator<Item = (Option<&'a str>, Option<&'a str>)>,
+    options: &HeatmapOptions,
+) -> Result<HeatmapResult> {
+    let mut builder = HeatmapBuilder::new(options.clone())?;
+    for (polyline_str, owner) in polylines {
+        match polyline_str {
+            Some(polyline_str) => builder.add_input(HeatmapInput::polyline(polyline_str).with_owner(owner)),
+            None => builder.reject(
+                InputFormat::Unknown,
+                HeatmapError::Format("expected a polyline string".to_string()),
+            ),
         };
     }
-    
-    // If JSON parsing fails, treat as encoded polyline (Strava format)
-    let coords = decode_polyline(polyline_str);
-    if !coords.is_empty() {
-        filter_unrealistic_jumps(&coords)
-    } else {
-        Vec::new()
-    }
-}
-
-// Add a function to process polylines from strings
-#[wasm_bindgen]
-pub fn process_polylines(polylines: js_sys::Array) -> JsValue {
-    let mut all_tracks: Vec<Vec<[f64; 2]>> = Vec::new();
-
-    // Process each polyline string
-    for i in 0..polylines.length() {
-        if let Some(polyline_str) = polylines.get(i).as_string() {
-            let coords = process_polyline(&polyline_str);
-            if coords.len() > 1 {
-                let simplified = simplify_track(&coords, 0.00005);
-                if simplified.len() > 1 {
-                    all_tracks.push(simplified);
-                }
+    Ok(builder.finish())
+}
+
+// Clean one activity, given as the raw tracks of its segments (one per GPX
+// <trkseg>, so usually just one). Each is split at time gaps, jump filtered and
+// split at distance gaps, then dwell removal, smoothing and privacy zone
+// clipping apply to each piece. The activity's start and end are trimmed
+// across all its pieces before they're simplified, so segment boundaries
+// aren't trimmed. Per-point data stays aligned throughout. The start and end
+// after filtering, before any privacy measures, are added to `endpoints`.
+fn clean_activity(
+    segments: Vec<Track>,
+    options: &HeatmapOptions,
+    diagnostics: &mut InputDiagnostics,
+    endpoints: &mut Vec<[f64; 2]>,
+) -> Vec<Track> {
+    let seed = trim_seed(&segments, options);
+    let mut filtered: Vec<Track> = Vec::new();
+    for segment in segments.into_iter().filter(|segment| segment.len() >= 2) {
+        for piece in split_at_time_gaps(segment, options, diagnostics) {
+            for kept in filter_piece(piece, options, diagnostics) {
+                filtered.extend(split_at_distance_gaps(kept, options, diagnostics));
             }
         }
     }
+    filtered.retain(|piece| piece.len() >= 2);
+    if let (Some(first), Some(last)) = (filtered.first(), filtered.last()) {
+        endpoints.extend([first.coordinates[0], last.coordinates[last.len() - 1]]);
+    }
 
-    // Apply the same processing logic as GPX files
-    let result = create_heatmap_from_tracks(all_tracks);
-    
-    serde_wasm_bindgen::to_value(&result).unwrap_or(JsValue::NULL)
+    let mut visible = Vec::new();
+    for piece in filtered {
+        for moving in remove_dwells(piece, options, diagnostics) {
+            visible.extend(clip_to_zones(smooth(moving, options), options, diagnostics));
+        }
+    }
+
+    trim_ends(visible, seed, options, diagnostics)
+        .into_iter()
+        .filter_map(|piece| simplify_piece(piece, options, diagnostics))
+        .collect()
 }
 
-// Helper function to create heatmap from coordinate arrays
-fn create_heatmap_from_tracks(all_tracks: Vec<Vec<[f64; 2]>>) -> HeatmapResult {
-    // Create a segment usage map to count overlapping segments
-    let mut segment_usage: HashMap<String, u32> = HashMap::new();
-    
-    // Break each track into segments and count usage
-    for track in &all_tracks {
-        for window in track.windows(2) {
-            if let [start, end] = window {
-                let segment_key = create_segment_key(*start, *end);
-                *segment_usage.entry(segment_key).or_insert(0) += 1;
-            }
-        }
+fn filter_piece(track: Track, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    // Timestamps allow a speed check that can repair and split tracks; without
+    // them only gross jumps can be caught
+    if options.speed_filter && track.times.is_some() {
+        return filter_motion(track, options, diagnostics);
     }
-    
-    // Calculate frequency for each track based on its segments
-    let mut heatmap_tracks = Vec::new();
-    
-    for track in all_tracks {
-        if track.len() < 2 {
-            continue;
-        }
-        
-        // Calculate track frequency as the average frequency of its segments
-        let mut total_usage = 0;
-        let mut segment_count = 0;
-        
-        for window in track.windows(2) {
-            if let [start, end] = window {
-                let segment_key = create_segment_key(*start, *end);
-                if let Some(&usage) = segment_usage.get(&segment_key) {
-                    total_usage += usage;
-                    segment_count += 1;
-                }
-            }
-        }
-        
-        // Use average usage, with minimum of 1
-        let track_frequency = if segment_count > 0 {
-            (total_usage as f64 / segment_count as f64).round() as u32
-        } else {
-            1
-        };
-        
-        heatmap_tracks.push(HeatmapTrack {
-            coordinates: track,
-            frequency: track_frequency,
-        });
+
+    let keep = filter_unrealistic_jumps(&track.coordinates, options.max_jump_km, options.max_consecutive_bad);
+    diagnostics.dropped_jumps += track.len() - keep.len();
+    vec![track.select(&keep)]
+}
+
+fn simplify_piece(track: Track, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Option<Track> {
+    if track.len() < 2 {
+        return None;
     }
-    
-    // Find the maximum frequency for normalization
-    let max_frequency = heatmap_tracks.iter()
-        .map(|track| track.frequency)
-        .max()
-        .unwrap_or(1);
-    
-    HeatmapResult {
-        tracks: heatmap_tracks,
-        max_frequency,
+
+    let keep = simplify(&track.coordinates, options.simplify_mode, options.simplify_tolerance_m);
+    diagnostics.simplified_away += track.len() - keep.len();
+    let track = track.select(&keep);
+    if track.len() < 2 {
+        return None;
     }
+
+    diagnostics.tracks += 1;
+    Some(track)
 }
 
-fn round(value: f64) -> f64 {
-    (value * 100000.0).round() / 100000.0
+fn round(value: f64, precision: u32) -> f64 {
+    let scale = 10f64.powi(precision as i32);
+    (value * scale).round() / scale
 }
 
-#[wasm_bindgen]
-pub fn process_gpx_files(files: js_sys::Array) -> JsValue {
-    let mut all_tracks: Vec<Vec<[f64; 2]>> = Vec::new();
-    
-    // Parse all GPX and FIT files and extract tracks
-    for file_bytes in files.iter() {
-        let array = js_sys::Uint8Array::new(&file_bytes);
-        let bytes = array.to_vec();
+// Parse an RFC 3339 / ISO 8601 timestamp such as "2024-05-01T07:30:12Z" or
+// "2019-06-11T07:30:12-07:00" into Unix seconds
+fn parse_timestamp(value: &str) -> Option<f64> {
+    let bytes = value.trim().as_bytes();
+    if bytes.len() < 19 || bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b't' | b' ') || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        std::str::from_utf8(&bytes[range]).ok()?.parse().ok()
+    };
+    let year = number(0..4)?;
+    let month = number(5..7)?;
+    let day = number(8..10)?;
+    let hour = number(11..13)?;
+    let minute = number(14..16)?;
+    let second = number(17..19)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Optional fractional seconds
+    let mut pos = 19;
+    let mut fraction = 0.0;
+    if pos < bytes.len() && bytes[pos] == b'.' {
+        let start = pos + 1;
+        pos = start;
+        while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+            pos += 1;
+        }
+        fraction = std::str::from_utf8(&bytes[start - 1..pos]).ok()?.parse::<f64>().unwrap_or(0.0);
+    }
 
-        // Try to parse as GPX first
-        if let Ok(gpx) = read(Cursor::new(&bytes)) {
+    // Time zone offset; a missing zone is treated as UTC
+    let offset_seconds = match bytes.get(pos) {
+        None | Some(b'Z') | Some(b'z') => 0,
+        Some(&sign @ (b'+' | b'-')) => {
+            if bytes.len() < pos + 6 || bytes[pos + 3] != b':' {
+                return None;
+            }
+            let offset = number(pos + 1..pos + 3)? * 3600 + number(pos + 4..pos + 6)? * 60;
+            if sign == b'-' { -offset } else { offset }
+        }
+        _ => return None,
+    };
+
+    // Days since the Unix epoch (proleptic Gregorian calendar)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - offset_seconds;
+    Some(seconds as f64 + fraction)
+}
+
+// Parse a single GPX or FIT file into its activities, each a list of raw
+// tracks, one per GPX segment
+fn process_file(bytes: &[u8], options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Vec<Track>> {
+    let mut activities = Vec::new();
+
+    // Try to parse as GPX first
+    match read(Cursor::new(bytes)) {
+        Ok(gpx) => {
+            diagnostics.format = InputFormat::Gpx;
             for track in gpx.tracks {
+                let mut segments = Vec::new();
                 for segment in track.segments {
-                    let mut track_coords = Vec::new();
-                    
-                    for point in segment.points {
-                        let lat = round(point.point().y());
-                        let lon = round(point.point().x());
-                        
-                        // Validate coordinates to prevent globe-spanning lines
-                        if is_valid_coordinate(lat, lon) {
-                            track_coords.push([lat, lon]);
-                        }
-                    }
-                    
-                    if track_coords.len() > 1 {
-                        // Filter out tracks with unrealistic jumps
-                        let filtered_coords = filter_unrealistic_jumps(&track_coords);
-                        
-                        if filtered_coords.len() > 1 {
-                            // Less aggressive simplification to preserve track shape
-                            let simplified = simplify_track(&filtered_coords, 0.00005);
-                            if simplified.len() > 1 {
-                                all_tracks.push(simplified);
-                            }
-                        }
-                    }
+                    let coords: Vec<[f64; 2]> = segment
+                        .points
+                        .iter()
+                        .map(|point| {
+                            [
+                                round(point.point().y(), options.coordinate_precision),
+                                round(point.point().x(), options.coordinate_precision),
+                            ]
+                        })
+                        .collect();
+                    // Only kept when every point has a time
+                    let times: Option<Vec<f64>> = segment.points.iter().map(gpx_time).collect();
+
+                    // Validate coordinates to prevent globe-spanning lines
+                    let track = Track::from_points(&coords, None, times.as_deref());
+                    diagnostics.read_points(coords.len(), track.len());
+                    segments.push(track);
                 }
+                activities.push(segments);
             }
         }
         // Try to parse as FIT file if GPX parsing fails
-        else if is_fit_file(&bytes) {
+        Err(_) if is_fit_file(bytes) => {
+            diagnostics.format = InputFormat::Fit;
+
             // Custom FIT file parser for extracting GPS coordinates
-            let mut fit_parser = FitParser::new(bytes);
-            let fit_coordinates = fit_parser.parse_gps_coordinates();
-            
-            // Apply the same validation and filtering as GPX
-            if fit_coordinates.len() > 1 {
-                let filtered_coords = filter_unrealistic_jumps(&fit_coordinates);
-                
-                if filtered_coords.len() > 1 {
-                    let simplified = simplify_track(&filtered_coords, 0.00005);
-                    if simplified.len() > 1 {
-                        all_tracks.push(simplified);
-                    }
-                }
+            let mut fit_parser = FitParser::new(bytes, options);
+            let mut fit_points = fit_parser.parse_gps_coordinates();
+            if let Some(error) = fit_parser.error.take() {
+                diagnostics.error(error);
             }
-        }
-        // Skip files that aren't GPX or FIT
-        else {
-            continue;
-        }
-    }
-    
-    // Create a segment usage map to count overlapping segments
-    let mut segment_usage: HashMap<String, u32> = HashMap::new();
-    
-    // Break each track into segments and count usage
-    for track in &all_tracks {
-        for window in track.windows(2) {
-            if let [start, end] = window {
-                let segment_key = create_segment_key(*start, *end);
-                *segment_usage.entry(segment_key).or_insert(0) += 1;
+
+            // Lap and session messages only carry summary positions (often the
+            // start, written after all records); when timestamped records are
+            // available they are the track
+            if fit_points.iter().any(|(_, time)| time.is_some()) {
+                fit_points.retain(|(_, time)| time.is_some());
             }
+            let fit_coordinates: Vec<[f64; 2]> = fit_points.iter().map(|(coord, _)| *coord).collect();
+            let fit_times: Option<Vec<f64>> = fit_points.iter().map(|(_, time)| *time).collect();
+
+            // Apply the same validation as GPX
+            let track = Track::from_points(&fit_coordinates, None, fit_times.as_deref());
+            diagnostics.read_points(fit_coordinates.len(), track.len());
+            activities.push(vec![track]);
         }
-    }
-    
-    // Calculate frequency for each track based on its segments
-    let mut heatmap_tracks = Vec::new();
-    let mut max_frequency = 0;
-    
-    for track in all_tracks {
-        if track.len() < 2 {
-            continue;
-        }
-        
-        // Calculate track frequency as the average frequency of its segments
-        let mut total_usage = 0;
-        let mut segment_count = 0;
-        
-        for window in track.windows(2) {
-            if let [start, end] = window {
-                let segment_key = create_segment_key(*start, *end);
-                if let Some(&usage) = segment_usage.get(&segment_key) {
-                    total_usage += usage;
-                    segment_count += 1;
-                }
+        // Report files that aren't GPX or FIT
+        Err(error) => {
+            if looks_like_xml(bytes) {
+                diagnostics.format = InputFormat::Gpx;
+                diagnostics.error(HeatmapError::Parse(format!("invalid GPX: {}", error)));
+            } else {
+                diagnostics.error(HeatmapError::Format("not a GPX or FIT file".to_string()));
             }
         }
-        
-        // Use average usage, with minimum of 1
-        let track_frequency = if segment_count > 0 {
-            (total_usage as f64 / segment_count as f64).round() as u32
-        } else {
-            1
-        };
-        
-        max_frequency = max_frequency.max(track_frequency);
-        
-        heatmap_tracks.push(HeatmapTrack {
-            coordinates: track,
-            frequency: track_frequency,
-        });
     }
-    
-    let result = HeatmapResult {
-        tracks: heatmap_tracks,
-        max_frequency,
-    };
-    
-    serde_wasm_bindgen::to_value(&result).unwrap()
+
+    activities
 }
 
-fn create_segment_key(start: [f64; 2], end: [f64; 2]) -> String {
-    // Use a larger tolerance for less aggressive matching
-    let tolerance = 0.001; // About 100 meters
-    let snap_start = snap_to_grid(start, tolerance);
-    let snap_end = snap_to_grid(end, tolerance);
-    
-    // Normalize direction (smaller coordinate first)
-    let (p1, p2) = if (snap_start[0], snap_start[1]) < (snap_end[0], snap_end[1]) {
-        (snap_start, snap_end)
-    } else {
-        (snap_end, snap_start)
-    };
-    
-    format!("{:.4},{:.4}-{:.4},{:.4}", p1[0], p1[1], p2[0], p2[1])
+fn gpx_time(point: &gpx::Waypoint) -> Option<f64> {
+    let time = point.time?.format().ok()?;
+    parse_timestamp(&time)
 }
 
-fn snap_to_grid(point: [f64; 2], tolerance: f64) -> [f64; 2] {
-    [
-        (point[0] / tolerance).round() * tolerance,
-        (point[1] / tolerance).round() * tolerance,
-    ]
+// Cheap check for XML content, used to tell broken GPX apart from other files
+fn looks_like_xml(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    data.iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|&b| b == b'<')
 }
 
-fn simplify_track(points: &[[f64; 2]], tolerance: f64) -> Vec<[f64; 2]> {
+// Returns the indices of the points to keep; tolerance is in meters
+fn simplify_track(points: &[[f64; 2]], tolerance: f64) -> Vec<usize> {
     if points.len() <= 2 {
-        return points.to_vec();
+        return (0..points.len()).collect();
     }
     
-    let mut result = vec![points[0]];
+    let mut result = vec![0];
     let mut last_added = 0;
     
     for i in 1..points.len() {
@@ -359,7 +640,7 @@ fn simplify_track(points: &[[f64; 2]], tolerance: f64) -> Vec<[f64; 2]> {
         // Add point if it's far enough from the last added point
         // or if it's the last point in the track
         if distance > tolerance || i == points.len() - 1 {
-            result.push(points[i]);
+            result.push(i);
             last_added = i;
         }
     }
@@ -367,15 +648,21 @@ fn simplify_track(points: &[[f64; 2]], tolerance: f64) -> Vec<[f64; 2]> {
     result
 }
 
+// Length of a degree of latitude, and of longitude at the equator
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+// Distance in meters using a local equirectangular projection, which is
+// accurate for the short hops between neighbouring track points
 fn distance(p1: [f64; 2], p2: [f64; 2]) -> f64 {
-    let dx = p1[0] - p2[0];
-    let dy = p1[1] - p2[1];
+    let mean_latitude = ((p1[0] + p2[0]) / 2.0).to_radians();
+    let dy = (p1[0] - p2[0]) * METERS_PER_DEGREE;
+    let dx = (p1[1] - p2[1]) * METERS_PER_DEGREE * mean_latitude.cos();
     (dx * dx + dy * dy).sqrt()
 }
 
 fn is_valid_coordinate(lat: f64, lon: f64) -> bool {
     // Check for valid latitude and longitude ranges
-    if lat < -90.0 || lat > 90.0 || lon < -180.0 || lon > 180.0 {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
         return false;
     }
     
@@ -387,18 +674,17 @@ fn is_valid_coordinate(lat: f64, lon: f64) -> bool {
     true
 }
 
-fn filter_unrealistic_jumps(coords: &[[f64; 2]]) -> Vec<[f64; 2]> {
+// Returns the indices of the points that survive jump filtering
+fn filter_unrealistic_jumps(coords: &[[f64; 2]], max_jump_km: f64, max_consecutive_bad: usize) -> Vec<usize> {
     if coords.len() <= 1 {
-        return coords.to_vec();
+        return (0..coords.len()).collect();
     }
     
-    let mut filtered = vec![coords[0]];
-    let max_jump_km = 100.0; // Back to 100km for stricter filtering
+    let mut filtered = vec![0];
     let mut consecutive_bad_points = 0;
-    const MAX_CONSECUTIVE_BAD: usize = 10; // Allow up to 10 consecutive bad points
     
     for i in 1..coords.len() {
-        let prev = filtered.last().unwrap();
+        let prev = coords[*filtered.last().unwrap()];
         let curr = coords[i];
         
         // Calculate approximate distance in kilometers using Haversine formula
@@ -406,25 +692,21 @@ fn filter_unrealistic_jumps(coords: &[[f64; 2]]) -> Vec<[f64; 2]> {
         
         // Only add point if it's within reasonable distance from previous point
         if distance_km <= max_jump_km {
-            filtered.push(curr);
+            filtered.push(i);
             consecutive_bad_points = 0; // Reset bad point counter
         } else {
             consecutive_bad_points += 1;
             
             // If we've seen too many consecutive bad points, try to find good data ahead
-            if consecutive_bad_points <= MAX_CONSECUTIVE_BAD {
+            if consecutive_bad_points <= max_consecutive_bad {
                 // Look ahead up to 20 points to see if we can find a reasonable continuation
-                let mut found_good_continuation = false;
-                for j in (i + 1)..(i + 21).min(coords.len()) {
-                    let future_point = coords[j];
+                let look_ahead = &coords[(i + 1).min(coords.len())..(i + 21).min(coords.len())];
+                let found_good_continuation = look_ahead.iter().any(|future_point| {
                     let future_distance = haversine_distance(prev[0], prev[1], future_point[0], future_point[1]);
                     
                     // If we find a reasonable point ahead, it suggests this is just a GPS glitch
-                    if future_distance <= max_jump_km * 1.5 { // Allow 1.5x distance for bridging
-                        found_good_continuation = true;
-                        break;
-                    }
-                }
+                    future_distance <= max_jump_km * 1.5 // Allow 1.5x distance for bridging
+                });
                 
                 // If no good continuation found, we might be at the end of good data
                 if !found_good_continuation {
@@ -435,15 +717,14 @@ fn filter_unrealistic_jumps(coords: &[[f64; 2]]) -> Vec<[f64; 2]> {
                         
                         // If we find a reasonable point, start a new segment from there
                         if remaining_distance <= max_jump_km {
-                            filtered.push(remaining_point);
+                            filtered.push(k);
                             // Continue filtering from this new point
-                            for m in (k + 1)..coords.len() {
-                                let next_prev = filtered.last().unwrap();
-                                let next_curr = coords[m];
+                            for (m, next_curr) in coords.iter().enumerate().skip(k + 1) {
+                                let next_prev = coords[*filtered.last().unwrap()];
                                 let next_distance = haversine_distance(next_prev[0], next_prev[1], next_curr[0], next_curr[1]);
                                 
                                 if next_distance <= max_jump_km {
-                                    filtered.push(next_curr);
+                                    filtered.push(m);
                                 }
                                 // Skip points that are too far, but don't break - keep looking
                             }
@@ -479,10 +760,13 @@ fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
 // Custom FIT file parser for extracting GPS coordinates
 // FIT file format reference: https://developer.garmin.com/fit/protocol/
 
-struct FitParser {
-    data: Vec<u8>,
+struct FitParser<'a> {
+    data: &'a [u8],
+    options: &'a HeatmapOptions,
     pos: usize,
     message_definitions: HashMap<u8, MessageDefinition>,
+    // Why parsing stopped early, if it did
+    error: Option<HeatmapError>,
 }
 
 #[derive(Clone)]
@@ -498,12 +782,14 @@ struct FieldDefinition {
     _base_type: u8,
 }
 
-impl FitParser {
-    fn new(data: Vec<u8>) -> Self {
+impl<'a> FitParser<'a> {
+    fn new(data: &'a [u8], options: &'a HeatmapOptions) -> Self {
         Self { 
             data, 
+            options,
             pos: 0,
             message_definitions: HashMap::new(),
+            error: None,
         }
     }
 
@@ -561,17 +847,20 @@ impl FitParser {
         self.pos = (self.pos + bytes).min(self.data.len());
     }
 
-    fn parse_gps_coordinates(&mut self) -> Vec<[f64; 2]> {
+    // Points in file order, with their record timestamp (Unix seconds) if any
+    fn parse_gps_coordinates(&mut self) -> Vec<([f64; 2], Option<f64>)> {
         let mut coordinates = Vec::new();
 
         // Check FIT file header
         if self.data.len() < 14 {
+            self.error = Some(HeatmapError::Parse("FIT file is too short".to_string()));
             return coordinates;
         }
 
         // FIT file header (14 bytes)
         let header_size = self.read_u8().unwrap_or(0);
         if header_size < 12 {
+            self.error = Some(HeatmapError::Parse(format!("invalid FIT header size {}", header_size)));
             return coordinates;
         }
 
@@ -587,6 +876,7 @@ impl FitParser {
             self.read_u8().unwrap_or(0),
         ];
         if signature != [b'.', b'F', b'I', b'T'] {
+            self.error = Some(HeatmapError::Parse("missing .FIT signature".to_string()));
             return coordinates;
         }
 
@@ -603,7 +893,8 @@ impl FitParser {
         let data_end = header_data_end.max(file_data_end); // Use the larger of the two
         
         let mut consecutive_errors = 0;
-        const MAX_CONSECUTIVE_ERRORS: usize = 100; // Allow more errors before giving up
+        let max_consecutive_errors = self.options.fit_max_consecutive_errors;
+        let max_message_size = self.options.fit_max_message_size;
         let mut processed_bytes = 0;
         let mut last_progress_pos = self.pos;
 
@@ -654,7 +945,7 @@ impl FitParser {
                     let total_size: usize = definition.fields.iter().map(|f| f.size as usize).sum();
                     if self.pos + total_size > self.data.len() {
                         // Not enough bytes left, try to parse what we can or skip this message
-                        if total_size < 1000 { // Only try if it's a reasonable size
+                        if total_size < max_message_size { // Only try if it's a reasonable size
                             self.skip(self.data.len() - self.pos); // Skip to end
                         }
                         break;
@@ -664,35 +955,29 @@ impl FitParser {
                     match definition.global_message_number {
                         20 => {
                             // Record message (primary GPS data)
-                            if let Some(coord) = self.parse_record_message(&definition) {
-                                if is_valid_coordinate(coord[0], coord[1]) {
-                                    coordinates.push(coord);
-                                }
+                            if let Some(point) = self.parse_record_message(&definition) {
+                                coordinates.push(point);
                             }
                             true
                         }
                         19 => {
                             // Lap message (might contain GPS data)
                             if let Some(coord) = self.parse_flexible_gps_message(&definition) {
-                                if is_valid_coordinate(coord[0], coord[1]) {
-                                    coordinates.push(coord);
-                                }
+                                coordinates.push((coord, None));
                             }
                             true
                         }
                         18 => {
                             // Session message (might contain GPS data)
                             if let Some(coord) = self.parse_flexible_gps_message(&definition) {
-                                if is_valid_coordinate(coord[0], coord[1]) {
-                                    coordinates.push(coord);
-                                }
+                                coordinates.push((coord, None));
                             }
                             true
                         }
                         _ => {
                             // Skip other message types but don't count as error
                             let total_size: usize = definition.fields.iter().map(|f| f.size as usize).sum();
-                            if total_size < 1000 && self.pos + total_size <= self.data.len() {
+                            if total_size < max_message_size && self.pos + total_size <= self.data.len() {
                                 self.skip(total_size);
                             } else {
                                 // Skip to end if message is too large or would overflow
@@ -720,13 +1005,17 @@ impl FitParser {
                 }
                 
                 // Only give up if we hit way too many consecutive errors AND we haven't found much data
-                if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+                if consecutive_errors >= max_consecutive_errors {
                     // If we have a decent amount of coordinates, maybe this is just the end of useful data
                     if coordinates.len() < 100 {
+                        self.error = Some(HeatmapError::LimitExceeded {
+                            limit: "consecutive unreadable FIT records",
+                            value: consecutive_errors,
+                        });
                         break; // Give up if we don't have much data
                     } else {
                         // We have good data, try to continue a bit more
-                        consecutive_errors = MAX_CONSECUTIVE_ERRORS / 2; // Reset to half
+                        consecutive_errors = max_consecutive_errors / 2; // Reset to half
                     }
                 }
             }
@@ -790,9 +1079,10 @@ impl FitParser {
         })
     }
 
-    fn parse_record_message(&mut self, definition: &MessageDefinition) -> Option<[f64; 2]> {
+    fn parse_record_message(&mut self, definition: &MessageDefinition) -> Option<([f64; 2], Option<f64>)> {
         let mut lat: Option<f64> = None;
         let mut lon: Option<f64> = None;
+        let mut time: Option<f64> = None;
 
         for field in &definition.fields {
             // More defensive bounds checking
@@ -834,6 +1124,18 @@ impl FitParser {
                         self.skip(field.size as usize);
                     }
                 }
+                253 => {
+                    // Timestamp, seconds since the FIT epoch
+                    if field.size == 4 {
+                        if let Some(raw) = self.read_u32_le() {
+                            if raw != 0xFFFFFFFF {
+                                time = Some(raw as f64 + FIT_EPOCH_OFFSET);
+                            }
+                        }
+                    } else {
+                        self.skip(field.size as usize);
+                    }
+                }
                 _ => {
                     // Skip other fields
                     self.skip(field.size as usize);
@@ -842,7 +1144,11 @@ impl FitParser {
         }
 
         if let (Some(lat_val), Some(lon_val)) = (lat, lon) {
-            Some([round(lat_val), round(lon_val)])
+            let coord = [
+                round(lat_val, self.options.coordinate_precision),
+                round(lon_val, self.options.coordinate_precision),
+            ];
+            Some((coord, time))
         } else {
             None
         }
@@ -888,13 +1194,19 @@ impl FitParser {
         }
 
         if let (Some(lat_val), Some(lon_val)) = (lat, lon) {
-            Some([round(lat_val), round(lon_val)])
+            Some([
+                round(lat_val, self.options.coordinate_precision),
+                round(lon_val, self.options.coordinate_precision),
+            ])
         } else {
             None
         }
     }
 }
 
+// Seconds from the Unix epoch to the FIT epoch, 1989-12-31T00:00:00Z
+const FIT_EPOCH_OFFSET: f64 = 631_065_600.0;
+
 fn is_fit_file(data: &[u8]) -> bool {
     if data.len() < 12 {
         return false;
@@ -0,0 +1,52 @@
+// Level-of-detail geometry. For each zoom level in `lod_zooms`, lines are
+// simplified with Douglas-Peucker at a tolerance of `lod_tolerance_px` screen
+// pixels, so a renderer can swap to the coarser geometry when zoomed out.
+// Frequencies are unaffected; only the coordinates are thinned.
+
+use serde::Serialize;
+
+use crate::simplify::{simplify, SimplifyMode};
+use crate::{HeatmapOptions, HeatmapResult};
+
+// Web Mercator ground resolution at the equator for zoom 0 with 256 px tiles
+const METERS_PER_PIXEL_ZOOM_0: f64 = 156_543.034;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LevelOfDetail {
+    pub zoom: u8,
+    // [lat, lon] pairs, a subset of the full-resolution coordinates
+    pub coordinates: Vec<[f64; 2]>,
+}
+
+pub(crate) fn add_levels(result: &mut HeatmapResult, options: &HeatmapOptions) {
+    if options.lod_zooms.is_empty() {
+        return;
+    }
+    for track in &mut result.tracks {
+        track.lod = levels(&track.coordinates, options);
+    }
+    if let Some(network) = &mut result.network {
+        for edge in &mut network.edges {
+            edge.lod = levels(&edge.coordinates, options);
+        }
+    }
+}
+
+fn levels(coordinates: &[[f64; 2]], options: &HeatmapOptions) -> Vec<LevelOfDetail> {
+    let Some(&[latitude, _]) = coordinates.first() else {
+        return Vec::new();
+    };
+
+    options
+        .lod_zooms
+        .iter()
+        .map(|&zoom| {
+            let meters_per_pixel = METERS_PER_PIXEL_ZOOM_0 * latitude.to_radians().cos() / 2f64.powi(zoom as i32);
+            let keep = simplify(coordinates, SimplifyMode::DouglasPeucker, meters_per_pixel * options.lod_tolerance_px);
+            LevelOfDetail {
+                zoom,
+                coordinates: keep.iter().map(|&i| coordinates[i]).collect(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,266 @@
+// heatmap-parse: build a heatmap from a directory of GPX, FIT and polyline files
+//
+// Polyline files (.polyline or .txt) hold one encoded polyline or JSON
+// coordinate array per line. Processing options can come from a JSON file
+// (--options) and from individual flags such as --grid-size-m 50; flags win.
+// With --owner-per-dir, each top-level directory under a PATH is one owner for
+// min_owners, e.g. club/alice/*.gpx and club/bob/*.fit.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use heatmap_parse::{HeatmapBuilder, HeatmapInput, HeatmapOptions};
+use serde_json::{Map, Value};
+
+const USAGE: &str = "\
+Usage: heatmap-parse [OPTIONS] <PATH>...
+
+Walks each PATH (a directory or a single file) for .gpx, .fit, .polyline and
+.txt files and writes the aggregated heatmap as JSON or GeoJSON.
+
+Options:
+  -o, --output <FILE>     Write to FILE instead of stdout
+  -f, --format <FORMAT>   json (default) or geojson
+      --options <FILE>    Read processing options from a JSON file
+      --<option> <VALUE>  Set a processing option, e.g. --grid-size-m 50 or
+                          --max-jump-km 20 (any HeatmapOptions field, in kebab-case)
+      --owner-per-dir     Treat each directory directly under a PATH as one owner,
+                          for --min-owners; other files share an unknown owner
+  -q, --quiet             Don't report skipped or failed inputs on stderr
+  -h, --help              Print this help
+";
+
+#[derive(PartialEq)]
+enum OutputFormat {
+    Json,
+    GeoJson,
+}
+
+struct Args {
+    paths: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    format: OutputFormat,
+    options: HeatmapOptions,
+    owner_per_dir: bool,
+    quiet: bool,
+}
+
+// Where an input came from, for the stderr report
+struct Source {
+    path: PathBuf,
+    line: Option<usize>,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(message) => {
+            eprintln!("heatmap-parse: {}\n\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("heatmap-parse: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let mut files = Vec::new();
+    for root in &args.paths {
+        let mut found = Vec::new();
+        collect_files(root, &mut found)?;
+        for path in found {
+            let owner = if args.owner_per_dir { top_level_dir(root, &path) } else { None };
+            files.push((path, owner));
+        }
+    }
+    files.sort();
+
+    // Files are read and added one at a time, so memory stays bounded by the
+    // aggregated heatmap rather than the size of the archive
+    let mut builder = HeatmapBuilder::new(args.options.clone()).map_err(|e| e.to_string())?;
+    let mut sources = Vec::new();
+    for (path, owner) in files {
+        let bytes = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        builder.set_owner(owner.as_deref());
+        if is_polyline_file(&path) {
+            let text = String::from_utf8_lossy(&bytes);
+            for (line_number, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if !line.is_empty() {
+                    sources.push(Source { path: path.clone(), line: Some(line_number + 1) });
+                    builder.add_input(HeatmapInput::polyline(line));
+                }
+            }
+        } else {
+            builder.add_input(HeatmapInput::file(&bytes));
+            sources.push(Source { path, line: None });
+        }
+    }
+    let result = builder.finish();
+
+    if !args.quiet {
+        for diagnostics in &result.diagnostics {
+            if diagnostics.errors.is_empty() && diagnostics.tracks > 0 {
+                continue;
+            }
+            let source = &sources[diagnostics.index];
+            let location = match source.line {
+                Some(line) => format!("{}:{}", source.path.display(), line),
+                None => source.path.display().to_string(),
+            };
+            if diagnostics.errors.is_empty() {
+                eprintln!("{}: no usable track ({} points read)", location, diagnostics.points_read);
+            }
+            for error in &diagnostics.errors {
+                eprintln!("{}: {}", location, error);
+            }
+        }
+        eprintln!(
+            "{} inputs, {} tracks, max frequency {}",
+            result.diagnostics.len(),
+            result.tracks.len(),
+            result.max_frequency
+        );
+    }
+
+    let json = match args.format {
+        OutputFormat::Json => serde_json::to_string(&result),
+        OutputFormat::GeoJson => serde_json::to_string(&result.to_geojson()),
+    }
+    .map_err(|e| e.to_string())?;
+
+    match &args.output {
+        Some(path) => fs::write(path, json).map_err(|e| format!("{}: {}", path.display(), e)),
+        None => {
+            let mut stdout = io::stdout().lock();
+            writeln!(stdout, "{}", json).map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Returns Ok(None) when help was requested
+fn parse_args(mut raw: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut paths = Vec::new();
+    let mut output = None;
+    let mut format = OutputFormat::Json;
+    let mut options_file = None;
+    let mut overrides = Map::new();
+    let mut owner_per_dir = false;
+    let mut quiet = false;
+
+    while let Some(arg) = raw.next() {
+        let mut value = |name: &str| raw.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-q" | "--quiet" => quiet = true,
+            "--owner-per-dir" => owner_per_dir = true,
+            "-o" | "--output" => output = Some(PathBuf::from(value(&arg)?)),
+            "-f" | "--format" => {
+                format = match value(&arg)?.as_str() {
+                    "json" => OutputFormat::Json,
+                    "geojson" => OutputFormat::GeoJson,
+                    other => return Err(format!("unknown format '{}'", other)),
+                }
+            }
+            "--options" => options_file = Some(PathBuf::from(value(&arg)?)),
+            flag if flag.starts_with("--") => {
+                // Any other long flag sets the processing option of the same name
+                let name = flag.trim_start_matches("--").replace('-', "_");
+                let raw_value = value(flag)?;
+                let parsed = serde_json::from_str(&raw_value).unwrap_or(Value::String(raw_value));
+                overrides.insert(name, parsed);
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("unknown flag '{}'", flag));
+            }
+            path => paths.push(PathBuf::from(path)),
+        }
+    }
+
+    if paths.is_empty() {
+        return Err("no input paths given".to_string());
+    }
+
+    let mut settings = match options_file {
+        Some(path) => {
+            let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            match serde_json::from_str(&text) {
+                Ok(Value::Object(settings)) => settings,
+                Ok(_) => return Err(format!("{}: options must be a JSON object", path.display())),
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            }
+        }
+        None => Map::new(),
+    };
+    settings.extend(overrides);
+    let options: HeatmapOptions =
+        serde_json::from_value(Value::Object(settings)).map_err(|e| format!("invalid options: {}", e))?;
+
+    Ok(Some(Args {
+        paths,
+        output,
+        format,
+        options,
+        owner_per_dir,
+        quiet,
+    }))
+}
+
+// Recursively collect supported files, skipping hidden files and directories
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if metadata.is_file() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("{}: {}", path.display(), e))?;
+        let entry_path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if hidden {
+            continue;
+        }
+        if entry_path.is_dir() {
+            collect_files(&entry_path, files)?;
+        } else if is_supported_file(&entry_path) {
+            files.push(entry_path);
+        }
+    }
+    Ok(())
+}
+
+// The first directory below `root` on the way to `path`, if there is one
+fn top_level_dir(root: &Path, path: &Path) -> Option<String> {
+    let mut components = path.strip_prefix(root).ok()?.components();
+    let first = components.next()?;
+    components.next()?;
+    Some(first.as_os_str().to_string_lossy().into_owned())
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+fn is_supported_file(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "gpx" | "fit") || is_polyline_file(path)
+}
+
+fn is_polyline_file(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "polyline" | "txt")
+}
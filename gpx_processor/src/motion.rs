@@ -0,0 +1,86 @@
+// Timestamp-based outlier filter. With times available, each point is checked
+// against the last accepted one for an implied speed above the sport's limit or
+// an implausible change in speed. A short run of bad points followed by a
+// return to plausible motion is a GPS spike and is removed; motion that never
+// comes back is a teleport (a paused recording, a train, a bad fix that
+// stuck), and the track is split there instead of being dropped.
+
+use crate::{distance, HeatmapOptions, InputDiagnostics, Track};
+
+// Speed limits in km/h by sport, for activities whose sport is known.
+// Generous on purpose: these catch GPS errors, not fast riders.
+fn sport_max_speed_kmh(sport: Option<&str>) -> f64 {
+    let sport = sport.unwrap_or_default().to_ascii_lowercase();
+    let is = |keywords: &[&str]| keywords.iter().any(|keyword| sport.contains(keyword));
+
+    if is(&["swim"]) {
+        15.0
+    } else if is(&["row", "kayak", "canoe", "paddle", "sup"]) {
+        30.0
+    } else if is(&["run", "walk", "hike", "snowshoe"]) {
+        40.0
+    } else if is(&["ski", "snowboard", "sled"]) {
+        150.0
+    } else if is(&["ride", "cycl", "bik"]) {
+        120.0
+    } else {
+        // Unknown sport, e.g. a GPX or FIT file
+        250.0
+    }
+}
+
+// Returns the plausible pieces of the track, or the track unchanged when it has no times
+pub(crate) fn filter_motion(track: Track, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    let Some(times) = track.times.as_deref() else {
+        return vec![track];
+    };
+
+    let sport = track.activity.as_ref().and_then(|activity| activity.sport_type.as_deref());
+    let max_speed = options.max_speed_kmh.unwrap_or_else(|| sport_max_speed_kmh(sport)) / 3.6;
+    let max_acceleration = options.max_acceleration_ms2;
+    let coordinates = &track.coordinates;
+
+    // Speed in m/s between two points. Duplicate or out-of-order times count as one second.
+    let speed = |from: usize, to: usize| {
+        let seconds = (times[to] - times[from]).max(1.0);
+        distance(coordinates[from], coordinates[to]) / seconds
+    };
+    // Whether moving from `from` to `to` fits, given the speed into `from` (if known)
+    let plausible = |from: usize, to: usize, previous_speed: Option<f64>| {
+        let current = speed(from, to);
+        let seconds = (times[to] - times[from]).max(1.0);
+        current <= max_speed
+            && previous_speed.is_none_or(|previous| (current - previous).abs() / seconds <= max_acceleration)
+    };
+
+    let mut pieces: Vec<Vec<usize>> = Vec::new();
+    let mut piece = vec![0];
+    let mut previous_speed = None;
+    let mut next = 1;
+    while next < coordinates.len() {
+        let last = piece[piece.len() - 1];
+
+        // Accept the next point, or the first one after a short spike that fits
+        let lookahead = (next + options.max_consecutive_bad).min(coordinates.len() - 1);
+        match (next..=lookahead).find(|&candidate| plausible(last, candidate, previous_speed)) {
+            Some(accepted) => {
+                diagnostics.dropped_spikes += accepted - next;
+                previous_speed = Some(speed(last, accepted));
+                piece.push(accepted);
+                next = accepted + 1;
+            }
+            None => {
+                diagnostics.teleport_splits += 1;
+                pieces.push(std::mem::replace(&mut piece, vec![next]));
+                previous_speed = None;
+                next += 1;
+            }
+        }
+    }
+
+    if pieces.is_empty() && piece.len() == coordinates.len() {
+        return vec![track];
+    }
+    pieces.push(piece);
+    pieces.iter().map(|piece| track.pick(piece)).collect()
+}
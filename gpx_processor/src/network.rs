@@ -0,0 +1,235 @@
+// Road network view of the segment counts. Grid cells are nodes and counted
+// segments are edges; chains through cells with exactly two neighbours and the
+// same count on both sides are merged into one polyline, so a road ridden the
+// same number of times along its length becomes a single edge.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasherDefault;
+
+use serde::Serialize;
+
+use crate::grid::{
+    cell_pair_key, pack_cell, segment_cells, unpack_cell, CellHasher, Grid, SegmentCounts, SegmentKey,
+};
+use crate::LevelOfDetail;
+
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct HeatmapNetwork {
+    pub nodes: Vec<NetworkNode>,
+    pub edges: Vec<NetworkEdge>,
+}
+
+// A junction, dead end, or point where the count changes
+#[derive(Serialize, Clone, Debug)]
+pub struct NetworkNode {
+    // [lat, lon] of the cell center
+    pub coordinates: [f64; 2],
+    // Number of edges meeting here
+    pub degree: usize,
+    // Highest frequency of those edges
+    pub frequency: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NetworkEdge {
+    // Indices into `nodes`; equal for a closed loop
+    pub from: usize,
+    pub to: usize,
+    // [lat, lon] pairs from `from` to `to`
+    pub coordinates: Vec<[f64; 2]>,
+    pub frequency: u32,
+    // Simplified geometry per zoom level, when `lod_zooms` is set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lod: Vec<LevelOfDetail>,
+}
+
+type Cell = [i32; 2];
+
+struct Graph {
+    // Neighbours of each cell with the count of the segment to them, sorted
+    neighbours: HashMap<u64, Vec<(Cell, u32)>, BuildHasherDefault<CellHasher>>,
+}
+
+impl Graph {
+    fn new(segment_usage: &SegmentCounts) -> Self {
+        let mut neighbours: HashMap<u64, Vec<(Cell, u32)>, BuildHasherDefault<CellHasher>> = HashMap::default();
+        for (&key, &usage) in segment_usage {
+            let [a, b] = segment_cells(key);
+            // A segment that stays inside one cell doesn't connect anything
+            if a == b {
+                continue;
+            }
+            neighbours.entry(pack_cell(a)).or_default().push((b, usage));
+            neighbours.entry(pack_cell(b)).or_default().push((a, usage));
+        }
+        for list in neighbours.values_mut() {
+            list.sort_unstable();
+        }
+        Self { neighbours }
+    }
+
+    fn neighbours(&self, cell: Cell) -> &[(Cell, u32)] {
+        self.neighbours.get(&pack_cell(cell)).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // Cells where a merged edge has to end
+    fn is_node(&self, cell: Cell) -> bool {
+        match self.neighbours(cell) {
+            [(_, a), (_, b)] => a != b,
+            _ => true,
+        }
+    }
+}
+
+pub(crate) fn build_network(segment_usage: &SegmentCounts, grid: &Grid) -> HeatmapNetwork {
+    let graph = Graph::new(segment_usage);
+
+    let mut cells: Vec<Cell> = graph.neighbours.keys().map(|&packed| unpack_cell(packed)).collect();
+    cells.sort_unstable();
+
+    let mut network = HeatmapNetwork::default();
+    let mut node_ids: HashMap<u64, usize, BuildHasherDefault<CellHasher>> = HashMap::default();
+    let mut visited: HashSet<SegmentKey, BuildHasherDefault<CellHasher>> = HashSet::default();
+
+    let mut node_id = |network: &mut HeatmapNetwork, cell: Cell| {
+        *node_ids.entry(pack_cell(cell)).or_insert_with(|| {
+            let neighbours = graph.neighbours(cell);
+            network.nodes.push(NetworkNode {
+                coordinates: grid.center(cell),
+                degree: neighbours.len(),
+                frequency: neighbours.iter().map(|&(_, usage)| usage).max().unwrap_or(0),
+            });
+            network.nodes.len() - 1
+        })
+    };
+
+    // Walk from every node along each unvisited edge until the next node.
+    // Whatever is left afterwards are loops made only of chain cells; those
+    // start and end at their smallest cell.
+    let starts: Vec<Cell> = cells.iter().copied().filter(|&cell| graph.is_node(cell)).collect();
+    for start in starts.into_iter().chain(cells.iter().copied()) {
+        for &(next, usage) in graph.neighbours(start) {
+            if visited.contains(&cell_pair_key(start, next)) {
+                continue;
+            }
+            let (end, coordinates) = walk(&graph, &mut visited, start, next, grid);
+            let from = node_id(&mut network, start);
+            let to = node_id(&mut network, end);
+            network.edges.push(NetworkEdge {
+                from,
+                to,
+                coordinates,
+                frequency: usage,
+                lod: Vec::new(),
+            });
+        }
+    }
+
+    network
+}
+
+// Follow a chain from `start` through `next` until a node, or back to `start`
+fn walk(
+    graph: &Graph,
+    visited: &mut HashSet<SegmentKey, BuildHasherDefault<CellHasher>>,
+    start: Cell,
+    next: Cell,
+    grid: &Grid,
+) -> (Cell, Vec<[f64; 2]>) {
+    let mut coordinates = vec![grid.center(start)];
+    let (mut previous, mut current) = (start, next);
+    loop {
+        visited.insert(cell_pair_key(previous, current));
+        coordinates.push(grid.center(current));
+        if current == start || graph.is_node(current) {
+            return (current, coordinates);
+        }
+
+        // Chain cells have exactly two neighbours; carry on through the other one
+        let following = graph
+            .neighbours(current)
+            .iter()
+            .map(|&(cell, _)| cell)
+            .find(|&cell| cell != previous)
+            .unwrap_or(previous);
+        previous = current;
+        current = following;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Usage for the segments between consecutive cells of each path
+    fn usage(paths: &[(&[Cell], u32)]) -> SegmentCounts {
+        let mut usage = SegmentCounts::default();
+        for &(cells, count) in paths {
+            for pair in cells.windows(2) {
+                usage.insert(cell_pair_key(pair[0], pair[1]), count);
+            }
+        }
+        usage
+    }
+
+    fn network(paths: &[(&[Cell], u32)]) -> HeatmapNetwork {
+        build_network(&usage(paths), &Grid::new(100.0))
+    }
+
+    #[test]
+    fn merges_a_chain_into_one_edge() {
+        let network = network(&[(&[[0, 0], [1, 0], [2, 0], [3, 1], [4, 1]], 2)]);
+        assert_eq!(network.edges.len(), 1);
+        assert_eq!(network.nodes.len(), 2);
+        let edge = &network.edges[0];
+        assert_eq!(edge.coordinates.len(), 5);
+        assert_eq!(edge.frequency, 2);
+        assert!(network.nodes.iter().all(|node| node.degree == 1));
+    }
+
+    #[test]
+    fn splits_at_a_junction() {
+        // Three arms meeting at [0, 0]
+        let network = network(&[
+            (&[[0, 0], [1, 0], [2, 0]], 1),
+            (&[[0, 0], [-1, 0], [-2, 0]], 1),
+            (&[[0, 0], [0, 1], [0, 2]], 1),
+        ]);
+        assert_eq!(network.edges.len(), 3);
+        assert_eq!(network.nodes.len(), 4);
+        let junction = network.nodes.iter().filter(|node| node.degree == 3).count();
+        assert_eq!(junction, 1);
+        assert!(network.edges.iter().all(|edge| edge.coordinates.len() == 3));
+    }
+
+    #[test]
+    fn splits_where_the_count_changes() {
+        let network = network(&[(&[[0, 0], [1, 0], [2, 0]], 3), (&[[2, 0], [3, 0], [4, 0]], 1)]);
+        assert_eq!(network.edges.len(), 2);
+        let mut frequencies: Vec<u32> = network.edges.iter().map(|edge| edge.frequency).collect();
+        frequencies.sort_unstable();
+        assert_eq!(frequencies, [1, 3]);
+
+        // The shared cell is a node of degree 2 carrying the higher count
+        let middle = network.nodes.iter().find(|node| node.degree == 2).unwrap();
+        assert_eq!(middle.frequency, 3);
+    }
+
+    #[test]
+    fn closes_a_pure_loop() {
+        let network = network(&[(&[[0, 0], [0, 1], [1, 1], [1, 0], [0, 0]], 4)]);
+        assert_eq!(network.edges.len(), 1);
+        assert_eq!(network.nodes.len(), 1);
+        let edge = &network.edges[0];
+        assert_eq!(edge.from, edge.to);
+        assert_eq!(edge.coordinates.len(), 5);
+        assert_eq!(edge.coordinates.first(), edge.coordinates.last());
+        assert_eq!(edge.frequency, 4);
+    }
+
+    #[test]
+    fn ignores_segments_within_one_cell() {
+        let network = network(&[(&[[0, 0], [0, 0]], 1)]);
+        assert!(network.edges.is_empty() && network.nodes.is_empty());
+    }
+}
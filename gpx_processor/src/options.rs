@@ -0,0 +1,225 @@
+// Processing options shared by every entry point. From JS, pass a plain object
+// with any subset of these fields; missing fields take their defaults.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DwellAction, HeatmapError, PrivacyZone, Result, SimplifyMode, SmoothingMode};
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeatmapOptions {
+    // Minimum distance in meters between kept points when simplifying (default 5)
+    pub simplify_tolerance_m: f64,
+    // Which simplifier applies that tolerance (default Radius)
+    pub simplify_mode: SimplifyMode,
+    // Smoothing applied before simplification, so zig-zagging recordings of
+    // the same road line up (default Off)
+    pub smoothing: SmoothingMode,
+    // Points the moving average and Savitzky-Golay filters look at, odd (default 7)
+    pub smoothing_window: usize,
+    // Typical GPS position error in meters assumed by the Kalman filter (default 5)
+    pub kalman_measurement_noise_m: f64,
+    // Typical change in speed the Kalman filter allows for, in m/s² (default 1)
+    pub kalman_acceleration_noise_ms2: f64,
+    // Areas tracks are clipped out of, e.g. around home, so no segment inside
+    // one is output or counted (default none)
+    pub privacy_zones: Vec<PrivacyZone>,
+    // Meters removed from the start and the end of every activity, so it
+    // doesn't show where it began or ended (default 0)
+    pub trim_m: f64,
+    // Up to this many meters more are trimmed at each end, varying per activity (default 0)
+    pub trim_jitter_m: f64,
+    // Seed for the trim jitter; keep it private to make the cuts harder to undo (default 0)
+    pub trim_seed: u64,
+    // Grid cell size in meters used to match overlapping segments (default 100)
+    pub grid_size_m: f64,
+    // Spacing in meters tracks are resampled at before counting segments, so
+    // rides recorded at different sampling rates still match; None counts
+    // consecutive point pairs instead (default 25)
+    pub resample_spacing_m: Option<f64>,
+    // How the heatmap is broken into lines, each with its own frequency (default Tracks)
+    pub output_mode: OutputMode,
+    // Segments used by fewer distinct owners are left out of the output, so a
+    // shared map doesn't reveal anyone's own routes; see HeatmapBuilder::set_owner (default 1)
+    pub min_owners: u32,
+    // Zoom levels to add simplified geometry for, e.g. [4, 8, 12] (default none)
+    pub lod_zooms: Vec<u8>,
+    // Screen pixels a level's geometry may deviate from the full track (default 1)
+    pub lod_tolerance_px: f64,
+    // Decimal places GPX and FIT coordinates are rounded to (default 5, ~1 m)
+    pub coordinate_precision: u32,
+    // Split a timestamped track where consecutive points are more than this many
+    // seconds apart, e.g. a paused recording; None never splits on time (default 600)
+    pub max_gap_s: Option<f64>,
+    // Split a timestamped track where consecutive points are still more than this
+    // many meters apart after filtering, e.g. a train ride mid-activity. None
+    // never splits (default 5000)
+    pub max_gap_m: Option<f64>,
+    // The same for tracks without times, such as sparse polylines whose points
+    // can legitimately be kilometers apart. None never splits (default)
+    pub max_gap_m_untimed: Option<f64>,
+    // Points further than this from the previous point count as GPS glitches (default 100 km)
+    pub max_jump_km: f64,
+    // Consecutive glitches tolerated before the rest of a track is dropped, or
+    // with timestamps, the longest run of points removed as one spike (default 10)
+    pub max_consecutive_bad: usize,
+    // For tracks with timestamps, remove GPS spikes and split at teleports
+    // based on implied speed and acceleration instead of max_jump_km (default true)
+    pub speed_filter: bool,
+    // Speed limit for the speed filter; None picks one by sport, from 15 km/h
+    // for swims to 150 km/h for skiing and 250 km/h when the sport is unknown
+    pub max_speed_kmh: Option<f64>,
+    // Largest plausible change in speed between points, in m/s² (default 20)
+    pub max_acceleration_ms2: f64,
+    // Timestamped points that stay within dwell_radius_m of where they arrived
+    // for this many seconds form a dwell (a café stop, a long red light) and
+    // are collapsed or cut out; None keeps them (default). Slow real movement,
+    // such as a steep climb on foot, can stay within the radius that long too
+    pub dwell_min_duration_s: Option<f64>,
+    // Radius in meters a dwell's points stay within (default 25)
+    pub dwell_radius_m: f64,
+    // Whether a dwell becomes a single point or a break in the track (default Collapse)
+    pub dwell_action: DwellAction,
+    // List each dwell's location and duration in the input's diagnostics (default false)
+    pub report_dwells: bool,
+    // Consecutive unreadable FIT records before parsing gives up (default 100)
+    pub fit_max_consecutive_errors: usize,
+    // FIT data messages larger than this many bytes are treated as corrupt (default 1000)
+    pub fit_max_message_size: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    // One line per track, frequency averaged over the whole track
+    #[default]
+    Tracks,
+    // One two-point line per distinct grid segment, between cell centers
+    Segments,
+    // Tracks cut wherever their segment frequency changes, so heat varies along a route
+    SplitTracks,
+    // A graph of grid cells with equal-frequency chains merged into polylines
+    Network,
+}
+
+impl Default for HeatmapOptions {
+    fn default() -> Self {
+        Self {
+            simplify_tolerance_m: 5.0,
+            simplify_mode: SimplifyMode::Radius,
+            smoothing: SmoothingMode::Off,
+            smoothing_window: 7,
+            kalman_measurement_noise_m: 5.0,
+            kalman_acceleration_noise_ms2: 1.0,
+            privacy_zones: Vec::new(),
+            trim_m: 0.0,
+            trim_jitter_m: 0.0,
+            trim_seed: 0,
+            grid_size_m: 100.0,
+            resample_spacing_m: Some(25.0),
+            output_mode: OutputMode::Tracks,
+            min_owners: 1,
+            lod_zooms: Vec::new(),
+            lod_tolerance_px: 1.0,
+            coordinate_precision: 5,
+            max_gap_s: Some(600.0),
+            max_gap_m: Some(5000.0),
+            max_gap_m_untimed: None,
+            max_jump_km: 100.0,
+            max_consecutive_bad: 10,
+            speed_filter: true,
+            max_speed_kmh: None,
+            max_acceleration_ms2: 20.0,
+            dwell_min_duration_s: None,
+            dwell_radius_m: 25.0,
+            dwell_action: DwellAction::Collapse,
+            report_dwells: false,
+            fit_max_consecutive_errors: 100,
+            fit_max_message_size: 1000,
+        }
+    }
+}
+
+impl HeatmapOptions {
+    // Reject values that would make the pipeline misbehave rather than just tune it
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(HeatmapError::InvalidOptions(message.to_string()));
+
+        if !is_positive(self.simplify_tolerance_m) && self.simplify_tolerance_m != 0.0 {
+            return invalid("simplify_tolerance_m must be a non-negative number");
+        }
+        if self.smoothing_window < 3 || self.smoothing_window.is_multiple_of(2) {
+            return invalid("smoothing_window must be an odd number of at least 3");
+        }
+        if !is_positive(self.kalman_measurement_noise_m) {
+            return invalid("kalman_measurement_noise_m must be a positive number");
+        }
+        if !is_positive(self.kalman_acceleration_noise_ms2) {
+            return invalid("kalman_acceleration_noise_ms2 must be a positive number");
+        }
+        for zone in &self.privacy_zones {
+            if let Err(message) = zone.validate() {
+                return invalid(message);
+            }
+        }
+        if !is_positive(self.trim_m) && self.trim_m != 0.0 {
+            return invalid("trim_m must be a non-negative number");
+        }
+        if !is_positive(self.trim_jitter_m) && self.trim_jitter_m != 0.0 {
+            return invalid("trim_jitter_m must be a non-negative number");
+        }
+        // Cell indices are 32-bit, which covers the globe down to ~1 cm cells
+        if !is_positive(self.grid_size_m) || self.grid_size_m < 0.01 {
+            return invalid("grid_size_m must be at least 0.01");
+        }
+        if self.resample_spacing_m.is_some_and(|spacing| !is_positive(spacing) || spacing < 1.0) {
+            return invalid("resample_spacing_m must be at least 1");
+        }
+        if self.min_owners == 0 {
+            return invalid("min_owners must be at least 1");
+        }
+        if self.lod_zooms.iter().any(|&zoom| zoom > 24) {
+            return invalid("lod_zooms must be between 0 and 24");
+        }
+        if !is_positive(self.lod_tolerance_px) {
+            return invalid("lod_tolerance_px must be a positive number");
+        }
+        if self.coordinate_precision > 15 {
+            return invalid("coordinate_precision must be at most 15");
+        }
+        if self.max_gap_s.is_some_and(|gap| !is_positive(gap)) {
+            return invalid("max_gap_s must be a positive number");
+        }
+        if self.max_gap_m.is_some_and(|gap| !is_positive(gap)) {
+            return invalid("max_gap_m must be a positive number");
+        }
+        if self.max_gap_m_untimed.is_some_and(|gap| !is_positive(gap)) {
+            return invalid("max_gap_m_untimed must be a positive number");
+        }
+        if !is_positive(self.max_jump_km) {
+            return invalid("max_jump_km must be a positive number");
+        }
+        if self.max_speed_kmh.is_some_and(|speed| !is_positive(speed)) {
+            return invalid("max_speed_kmh must be a positive number");
+        }
+        if !is_positive(self.max_acceleration_ms2) {
+            return invalid("max_acceleration_ms2 must be a positive number");
+        }
+        if self.dwell_min_duration_s.is_some_and(|duration| !is_positive(duration)) {
+            return invalid("dwell_min_duration_s must be a positive number");
+        }
+        if !is_positive(self.dwell_radius_m) {
+            return invalid("dwell_radius_m must be a positive number");
+        }
+        if self.fit_max_consecutive_errors == 0 {
+            return invalid("fit_max_consecutive_errors must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+// Finite and greater than zero (false for NaN)
+fn is_positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
@@ -0,0 +1,253 @@
+// k-anonymity for shared heatmaps. Each input can carry an owner ID, and the
+// builder keeps, per segment, the distinct owners who used it. Segments with
+// fewer than `min_owners` owners are left out of every output, so a road only
+// one club member rides doesn't reveal their routes. Owner sets stop growing
+// at min_owners, since only reaching it matters. Inputs without an owner all
+// count as one unknown owner: treating each as a different person would let
+// one person's repeated rides pass for a crowd.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::grid::{SegmentCounts, SegmentKey, SegmentOwners};
+
+// Owner number shared by every input without an owner
+pub(crate) const UNKNOWN_OWNER: u32 = u32::MAX;
+
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Owners {
+    // Owner IDs as given, mapped to compact numbers
+    pub(crate) ids: HashMap<String, u32>,
+    // Next number to hand out
+    pub(crate) next: u32,
+    // Owner of the inputs being added, from `HeatmapBuilder::set_owner`
+    pub(crate) current: Option<u32>,
+}
+
+impl Owners {
+    pub(crate) fn set_current(&mut self, owner: Option<&str>) {
+        self.current = owner.map(|owner| self.number(owner));
+    }
+
+    // Owner number for an input: the owner given with it, else the current
+    // owner, else one from the input's own data such as a Strava athlete ID,
+    // else the unknown owner
+    pub(crate) fn input_owner(&mut self, owner: Option<&str>, from_data: Option<&str>) -> u32 {
+        match (owner, self.current, from_data) {
+            (Some(owner), _, _) => self.number(owner),
+            (None, Some(current), _) => current,
+            (None, None, Some(owner)) => self.number(owner),
+            (None, None, None) => UNKNOWN_OWNER,
+        }
+    }
+
+    fn number(&mut self, owner: &str) -> u32 {
+        if let Some(&number) = self.ids.get(owner) {
+            return number;
+        }
+        let number = self.next;
+        self.next += 1;
+        self.ids.insert(owner.to_string(), number);
+        number
+    }
+}
+
+// Record that `owner` used `key`, keeping at most min_owners owners
+pub(crate) fn add_owner(segment_owners: &mut SegmentOwners, key: SegmentKey, owner: u32, min_owners: u32) {
+    let owners = segment_owners.entry(key).or_default();
+    if owners.len() < min_owners as usize && !owners.contains(&owner) {
+        owners.push(owner);
+    }
+}
+
+// The counts of segments with at least min_owners owners
+pub(crate) fn visible_usage<'a>(
+    segment_usage: &'a SegmentCounts,
+    segment_owners: &SegmentOwners,
+    min_owners: u32,
+) -> Cow<'a, SegmentCounts> {
+    if min_owners <= 1 {
+        return Cow::Borrowed(segment_usage);
+    }
+    Cow::Owned(
+        segment_usage
+            .iter()
+            .filter(|(key, _)| segment_owners.get(key).is_some_and(|owners| owners.len() >= min_owners as usize))
+            .map(|(&key, &usage)| (key, usage))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        process_polyline_strings, process_strava, HeatmapBuilder, HeatmapInput, HeatmapOptions, OutputMode,
+        StravaActivity,
+    };
+
+    const ROUTE: &str = "[[47.6,-122.3],[47.605,-122.3],[47.61,-122.305]]";
+
+    fn options(min_owners: u32, output_mode: OutputMode) -> HeatmapOptions {
+        HeatmapOptions {
+            min_owners,
+            output_mode,
+            ..HeatmapOptions::default()
+        }
+    }
+
+    // Visible output lines, or network edges in network mode
+    fn visible(builder: &HeatmapBuilder) -> usize {
+        let result = builder.snapshot();
+        result.tracks.len() + result.network.map_or(0, |network| network.edges.len())
+    }
+
+    fn builder_with_owners(min_owners: u32, output_mode: OutputMode, owners: &[Option<&str>]) -> HeatmapBuilder {
+        let mut builder = HeatmapBuilder::new(options(min_owners, output_mode)).unwrap();
+        for &owner in owners {
+            builder.set_owner(owner);
+            builder.add_polyline(ROUTE);
+        }
+        builder
+    }
+
+    #[test]
+    fn same_owner_repeated_stays_hidden() {
+        let builder = builder_with_owners(2, OutputMode::Segments, &[Some("alice"); 5]);
+        assert_eq!(visible(&builder), 0);
+    }
+
+    #[test]
+    fn distinct_owners_around_k() {
+        let modes = [OutputMode::Tracks, OutputMode::Segments, OutputMode::SplitTracks, OutputMode::Network];
+        for mode in modes {
+            let below = builder_with_owners(3, mode, &[Some("alice"), Some("bob")]);
+            let at = builder_with_owners(3, mode, &[Some("alice"), Some("bob"), Some("carol")]);
+            let above = builder_with_owners(3, mode, &[Some("alice"), Some("bob"), Some("carol"), Some("dave")]);
+            assert_eq!(visible(&below), 0, "{:?}", mode);
+            assert!(visible(&at) > 0, "{:?}", mode);
+            assert!(visible(&above) >= visible(&at), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn inputs_without_an_owner_share_one() {
+        let untagged = builder_with_owners(2, OutputMode::Segments, &[None, None, None]);
+        assert_eq!(visible(&untagged), 0);
+
+        // set_owner(None) goes back to the unknown owner, which counts once
+        let reset = builder_with_owners(3, OutputMode::Segments, &[Some("alice"), None, None]);
+        assert_eq!(visible(&reset), 0);
+        let with_unknown = builder_with_owners(3, OutputMode::Segments, &[Some("alice"), Some("bob"), None]);
+        assert!(visible(&with_unknown) > 0);
+
+        let batch = process_polyline_strings(&[ROUTE, ROUTE, ROUTE], &options(3, OutputMode::Segments)).unwrap();
+        assert!(batch.tracks.is_empty());
+    }
+
+    #[test]
+    fn input_and_data_owners() {
+        // An input's own owner replaces the one from set_owner
+        let mut builder = HeatmapBuilder::new(options(2, OutputMode::Segments)).unwrap();
+        builder.set_owner(Some("alice"));
+        builder.add_polyline(ROUTE);
+        builder.add_input(HeatmapInput::polyline(ROUTE).with_owner(Some("alice")));
+        assert_eq!(visible(&builder), 0);
+        builder.add_input(HeatmapInput::polyline(ROUTE).with_owner(Some("bob")));
+        assert!(visible(&builder) > 0);
+
+        // Strava activities count as their athlete's
+        let activity = |athlete: u64| -> StravaActivity {
+            serde_json::from_value(serde_json::json!({
+                "id": 1,
+                "athlete": { "id": athlete },
+                "streams": { "latlng": { "data": serde_json::from_str::<serde_json::Value>(ROUTE).unwrap() } }
+            }))
+            .unwrap()
+        };
+        let options = options(2, OutputMode::Segments);
+        assert!(process_strava(vec![activity(7), activity(7)], &options).unwrap().tracks.is_empty());
+        assert!(!process_strava(vec![activity(7), activity(8)], &options).unwrap().tracks.is_empty());
+    }
+
+    #[test]
+    fn shared_tracks_leave_out_personal_details() {
+        let activity = |athlete: u64| -> StravaActivity {
+            serde_json::from_value(serde_json::json!({
+                "id": athlete,
+                "name": "Morning Ride",
+                "start_date": "2024-05-01T08:00:00Z",
+                "athlete": { "id": athlete },
+                "streams": {
+                    "latlng": { "data": serde_json::from_str::<serde_json::Value>(ROUTE).unwrap() },
+                    "time": { "data": [0, 120, 240] },
+                    "altitude": { "data": [10.0, 12.0, 15.0] }
+                }
+            }))
+            .unwrap()
+        };
+        for mode in [OutputMode::Tracks, OutputMode::SplitTracks] {
+            for min_owners in [1, 2] {
+                let mut builder = HeatmapBuilder::new(options(min_owners, mode)).unwrap();
+                builder.add_strava_activity(activity(7));
+                builder.add_strava_activity(activity(8));
+                let tracks = builder.snapshot().tracks;
+                assert!(!tracks.is_empty(), "{:?}", mode);
+                let shared = min_owners > 1;
+                for track in tracks {
+                    assert_eq!(track.times.is_none(), shared, "{:?}", mode);
+                    assert_eq!(track.elevations.is_none(), shared, "{:?}", mode);
+                    assert_eq!(track.activity.is_none(), shared, "{:?}", mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn owner_tables_survive_save_and_load() {
+        let builder = builder_with_owners(3, OutputMode::Segments, &[Some("alice"), Some("bob")]);
+
+        // alice again is still the same owner after a round trip
+        let mut again = HeatmapBuilder::load(&builder.save()).unwrap();
+        again.set_owner(Some("alice"));
+        again.add_polyline(ROUTE);
+        assert_eq!(visible(&again), 0);
+
+        // a third owner makes the route visible
+        let mut third = HeatmapBuilder::load(&builder.save()).unwrap();
+        third.set_owner(Some("carol"));
+        third.add_polyline(ROUTE);
+        assert!(visible(&third) > 0);
+
+        // the current owner is kept too
+        let mut current = HeatmapBuilder::load(&builder.save()).unwrap();
+        current.add_polyline(ROUTE);
+        assert_eq!(visible(&current), 0);
+    }
+
+    #[test]
+    fn partly_shared_routes_show_only_the_shared_part() {
+        // alice rides 2 km north, bob the first 1 km of it
+        let route = |points: usize| -> String {
+            let points: Vec<[f64; 2]> = (0..points).map(|i| [47.6 + i as f64 * 0.0001, -122.3]).collect();
+            serde_json::to_string(&points).unwrap()
+        };
+        for mode in [OutputMode::Tracks, OutputMode::SplitTracks, OutputMode::Segments] {
+            let mut builder = HeatmapBuilder::new(options(2, mode)).unwrap();
+            builder.set_owner(Some("alice"));
+            builder.add_polyline(&route(181));
+            builder.set_owner(Some("bob"));
+            builder.add_polyline(&route(91));
+
+            let result = builder.snapshot();
+            assert!(!result.tracks.is_empty(), "{:?}", mode);
+            // Up to one grid cell past where bob turned back
+            let furthest = result
+                .tracks
+                .iter()
+                .flat_map(|track| &track.coordinates)
+                .map(|point| point[0])
+                .fold(0.0, f64::max);
+            assert!(furthest < 47.609 + 0.001, "{:?} reaches {}", mode, furthest);
+        }
+    }
+}
@@ -0,0 +1,554 @@
+// Compact binary snapshot of a HeatmapBuilder, so aggregated state can be
+// cached (e.g. in IndexedDB) and resumed without re-parsing every activity.
+//
+// Layout, all integers little-endian:
+//   magic "HMAP", u16 format version
+//   options as JSON (u32 length + bytes)
+//   segment usage: u32 count, then (u128 segment key, u32 usage) pairs
+//   tracks: u32 count, then per track u32 point count, lat/lon f64 pairs,
+//     a flags byte and the optional elevations, times and activity metadata
+//   diagnostics: u32 count, then one record per input, ending with its
+//     u32 dwell count and per dwell lat, lon, start time, duration and u64 points
+//   activity endpoints: u32 count, then lat/lon f64 pairs
+//   segment owners: u32 count, then per segment its u128 key, a u32 owner
+//     count and the u32 owner numbers
+//   owners: u32 count, then (owner ID string, u32 number) pairs, the u32 next
+//     number and the optional u32 current owner
+// Strings are a u32 byte length followed by UTF-8. Bump FORMAT_VERSION whenever
+// the layout or the meaning of the stored data changes; older versions are rejected.
+
+use crate::grid::{SegmentCounts, SegmentKey, SegmentOwners};
+use crate::owners::Owners;
+use crate::{
+    is_valid_coordinate, ActivityMetadata, ActivitySource, Dwell, HeatmapBuilder, HeatmapError, HeatmapOptions, InputDiagnostics, InputFormat,
+    Result, Track,
+};
+
+const MAGIC: &[u8; 4] = b"HMAP";
+const FORMAT_VERSION: u16 = 1;
+
+const HAS_ELEVATIONS: u8 = 1;
+const HAS_TIMES: u8 = 2;
+const HAS_ACTIVITY: u8 = 4;
+
+impl HeatmapBuilder {
+    // Serialize the builder, including its options, for `HeatmapBuilder::load`
+    pub fn save(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.bytes(MAGIC);
+        writer.u16(FORMAT_VERSION);
+
+        // Options always serialize, they're plain numbers
+        let options = serde_json::to_vec(&self.options).unwrap_or_default();
+        writer.u32(options.len() as u32);
+        writer.bytes(&options);
+
+        // Sorted so the same state always saves to the same bytes
+        let mut segments: Vec<(&SegmentKey, &u32)> = self.segment_usage.iter().collect();
+        segments.sort();
+        writer.u32(segments.len() as u32);
+        for (key, usage) in segments {
+            writer.u128(*key);
+            writer.u32(*usage);
+        }
+
+        writer.u32(self.tracks.len() as u32);
+        for track in &self.tracks {
+            write_track(&mut writer, track);
+        }
+
+        writer.u32(self.diagnostics.len() as u32);
+        for diagnostics in &self.diagnostics {
+            write_diagnostics(&mut writer, diagnostics);
+        }
+
+        writer.u32(self.endpoints.len() as u32);
+        for [lat, lon] in &self.endpoints {
+            writer.f64(*lat);
+            writer.f64(*lon);
+        }
+
+        let mut segment_owners: Vec<(&SegmentKey, &Vec<u32>)> = self.segment_owners.iter().collect();
+        segment_owners.sort();
+        writer.u32(segment_owners.len() as u32);
+        for (key, owners) in segment_owners {
+            writer.u128(*key);
+            writer.u32(owners.len() as u32);
+            for owner in owners {
+                writer.u32(*owner);
+            }
+        }
+
+        let mut owner_ids: Vec<(&String, &u32)> = self.owners.ids.iter().collect();
+        owner_ids.sort_by_key(|&(_, number)| *number);
+        writer.u32(owner_ids.len() as u32);
+        for (id, number) in owner_ids {
+            writer.string(id);
+            writer.u32(*number);
+        }
+        writer.u32(self.owners.next);
+        writer.option(&self.owners.current, |w, current| w.u32(*current));
+
+        writer.buffer
+    }
+
+    // Restore a builder saved with `save`. Fails with a Format error if the
+    // data was written by a different format version.
+    pub fn load(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { data: bytes, pos: 0 };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(HeatmapError::Parse("not a saved heatmap state".to_string()));
+        }
+        let version = reader.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(HeatmapError::Format(format!(
+                "unsupported heatmap state version {} (expected {})",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let options_length = reader.u32()? as usize;
+        let options: HeatmapOptions = serde_json::from_slice(reader.take(options_length)?)?;
+        options.validate()?;
+
+        let segment_count = reader.u32()?;
+        let mut segment_usage = SegmentCounts::default();
+        for _ in 0..segment_count {
+            let key = reader.u128()?;
+            let usage = reader.u32()?;
+            segment_usage.insert(key, usage);
+        }
+
+        let track_count = reader.u32()?;
+        let mut tracks = Vec::new();
+        for _ in 0..track_count {
+            tracks.push(read_track(&mut reader)?);
+        }
+
+        let diagnostics_count = reader.u32()?;
+        let mut diagnostics = Vec::new();
+        for _ in 0..diagnostics_count {
+            diagnostics.push(read_diagnostics(&mut reader)?);
+        }
+
+        let endpoint_count = reader.u32()? as usize;
+        let mut endpoints = Vec::with_capacity(endpoint_count.min(reader.remaining() / 16));
+        for _ in 0..endpoint_count {
+            endpoints.push([reader.f64()?, reader.f64()?]);
+        }
+
+        let segment_owner_count = reader.u32()?;
+        let mut segment_owners = SegmentOwners::default();
+        for _ in 0..segment_owner_count {
+            let key = reader.u128()?;
+            let owner_count = reader.u32()?;
+            let owners = (0..owner_count).map(|_| reader.u32()).collect::<Result<Vec<u32>>>()?;
+            segment_owners.insert(key, owners);
+        }
+
+        let mut owners = Owners::default();
+        let owner_id_count = reader.u32()?;
+        for _ in 0..owner_id_count {
+            let id = reader.string()?;
+            owners.ids.insert(id, reader.u32()?);
+        }
+        owners.next = reader.u32()?;
+        owners.current = reader.option(Reader::u32)?;
+
+        if reader.pos != bytes.len() {
+            return Err(HeatmapError::Parse("unexpected data after heatmap state".to_string()));
+        }
+
+        Ok(Self {
+            options,
+            tracks,
+            segment_usage,
+            diagnostics,
+            endpoints,
+            segment_owners,
+            owners,
+        })
+    }
+}
+
+fn write_track(writer: &mut Writer, track: &Track) {
+    writer.u32(track.coordinates.len() as u32);
+    for [lat, lon] in &track.coordinates {
+        writer.f64(*lat);
+        writer.f64(*lon);
+    }
+
+    let mut flags = 0;
+    if track.elevations.is_some() {
+        flags |= HAS_ELEVATIONS;
+    }
+    if track.times.is_some() {
+        flags |= HAS_TIMES;
+    }
+    if track.activity.is_some() {
+        flags |= HAS_ACTIVITY;
+    }
+    writer.u8(flags);
+
+    for series in [&track.elevations, &track.times].into_iter().flatten() {
+        for value in series {
+            writer.f64(*value);
+        }
+    }
+
+    if let Some(activity) = &track.activity {
+        writer.u8(match activity.source {
+            ActivitySource::Strava => 0,
+            ActivitySource::Ridewithgps => 1,
+        });
+        writer.option(&activity.id, |w, id| w.u64(*id));
+        writer.option(&activity.name, |w, name| w.string(name));
+        writer.option(&activity.sport_type, |w, sport_type| w.string(sport_type));
+        writer.option(&activity.start_date, |w, start_date| w.string(start_date));
+        writer.option(&activity.distance, |w, distance| w.f64(*distance));
+    }
+}
+
+fn read_track(reader: &mut Reader) -> Result<Track> {
+    // The builder only keeps tracks of at least two valid points and relies on it
+    let length = reader.u32()? as usize;
+    if length < 2 {
+        return Err(HeatmapError::Parse(format!("track with {} points in heatmap state", length)));
+    }
+    let mut coordinates = Vec::with_capacity(length.min(reader.remaining() / 16));
+    for _ in 0..length {
+        let [lat, lon] = [reader.f64()?, reader.f64()?];
+        if !is_valid_coordinate(lat, lon) {
+            return Err(HeatmapError::Parse(format!("invalid coordinate {}, {} in heatmap state", lat, lon)));
+        }
+        coordinates.push([lat, lon]);
+    }
+
+    let flags = reader.u8()?;
+    let mut series = |flag: u8| -> Result<Option<Vec<f64>>> {
+        if flags & flag == 0 {
+            return Ok(None);
+        }
+        (0..length).map(|_| reader.f64()).collect::<Result<Vec<f64>>>().map(Some)
+    };
+    let elevations = series(HAS_ELEVATIONS)?;
+    let times = series(HAS_TIMES)?;
+
+    let activity = if flags & HAS_ACTIVITY != 0 {
+        let source = match reader.u8()? {
+            0 => ActivitySource::Strava,
+            1 => ActivitySource::Ridewithgps,
+            other => return Err(HeatmapError::Parse(format!("unknown activity source {}", other))),
+        };
+        Some(ActivityMetadata {
+            source,
+            id: reader.option(Reader::u64)?,
+            name: reader.option(Reader::string)?,
+            sport_type: reader.option(Reader::string)?,
+            start_date: reader.option(Reader::string)?,
+            distance: reader.option(Reader::f64)?,
+        })
+    } else {
+        None
+    };
+
+    Ok(Track {
+        coordinates,
+        elevations,
+        times,
+        activity,
+    })
+}
+
+const FORMATS: [InputFormat; 7] = [
+    InputFormat::Gpx,
+    InputFormat::Fit,
+    InputFormat::EncodedPolyline,
+    InputFormat::JsonCoordinates,
+    InputFormat::Strava,
+    InputFormat::Ridewithgps,
+    InputFormat::Unknown,
+];
+
+fn write_diagnostics(writer: &mut Writer, diagnostics: &InputDiagnostics) {
+    writer.u64(diagnostics.index as u64);
+    let format = FORMATS.iter().position(|&format| format == diagnostics.format).unwrap_or(FORMATS.len() - 1);
+    writer.u8(format as u8);
+    writer.u32(diagnostics.errors.len() as u32);
+    for error in &diagnostics.errors {
+        writer.string(error);
+    }
+    for count in [
+        diagnostics.points_read,
+        diagnostics.dropped_invalid,
+        diagnostics.gap_splits,
+        diagnostics.dropped_jumps,
+        diagnostics.dropped_spikes,
+        diagnostics.teleport_splits,
+        diagnostics.dwells_found,
+        diagnostics.dropped_dwell_points,
+        diagnostics.hidden_points,
+        diagnostics.trimmed_points,
+        diagnostics.simplified_away,
+        diagnostics.tracks,
+    ] {
+        writer.u64(count as u64);
+    }
+
+    writer.u32(diagnostics.dwells.len() as u32);
+    for dwell in &diagnostics.dwells {
+        for value in [dwell.coordinates[0], dwell.coordinates[1], dwell.start_time, dwell.duration_s] {
+            writer.f64(value);
+        }
+        writer.u64(dwell.points as u64);
+    }
+}
+
+fn read_diagnostics(reader: &mut Reader) -> Result<InputDiagnostics> {
+    let index = reader.u64()? as usize;
+    let format = match FORMATS.get(reader.u8()? as usize) {
+        Some(&format) => format,
+        None => return Err(HeatmapError::Parse("unknown input format".to_string())),
+    };
+    let mut diagnostics = InputDiagnostics::new(index, format);
+
+    let error_count = reader.u32()?;
+    for _ in 0..error_count {
+        diagnostics.errors.push(reader.string()?);
+    }
+    diagnostics.points_read = reader.u64()? as usize;
+    diagnostics.dropped_invalid = reader.u64()? as usize;
+    diagnostics.gap_splits = reader.u64()? as usize;
+    diagnostics.dropped_jumps = reader.u64()? as usize;
+    diagnostics.dropped_spikes = reader.u64()? as usize;
+    diagnostics.teleport_splits = reader.u64()? as usize;
+    diagnostics.dwells_found = reader.u64()? as usize;
+    diagnostics.dropped_dwell_points = reader.u64()? as usize;
+    diagnostics.hidden_points = reader.u64()? as usize;
+    diagnostics.trimmed_points = reader.u64()? as usize;
+    diagnostics.simplified_away = reader.u64()? as usize;
+    diagnostics.tracks = reader.u64()? as usize;
+
+    let dwell_count = reader.u32()?;
+    for _ in 0..dwell_count {
+        diagnostics.dwells.push(Dwell {
+            coordinates: [reader.f64()?, reader.f64()?],
+            start_time: reader.f64()?,
+            duration_s: reader.f64()?,
+            points: reader.u64()? as usize,
+        });
+    }
+    Ok(diagnostics)
+}
+
+#[derive(Default)]
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u128(&mut self, value: u128) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes(value.as_bytes());
+    }
+
+    // A presence byte followed by the value
+    fn option<T>(&mut self, value: &Option<T>, write: impl FnOnce(&mut Self, &T)) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                write(self, value);
+            }
+            None => self.u8(0),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.remaining() {
+            return Err(HeatmapError::Parse("truncated heatmap state".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| HeatmapError::Parse("invalid string in heatmap state".to_string()))
+    }
+
+    fn option<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            _ => Err(HeatmapError::Parse("invalid optional value in heatmap state".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_gpx::gpx_1hz;
+    use crate::{OutputMode, SimplifyMode};
+
+    // A timed GPX track heading north-east from `start`, one point a second
+    fn track(start: [f64; 2], points: usize) -> Vec<u8> {
+        gpx_1hz((0..points).map(|i| [start[0] + i as f64 * 0.00005, start[1] + i as f64 * 0.00003]))
+    }
+
+    fn builder(output_mode: OutputMode) -> HeatmapBuilder {
+        let options = HeatmapOptions {
+            output_mode,
+            // Straight tracks simplify to a few points, keeping the state small
+            simplify_mode: SimplifyMode::DouglasPeucker,
+            min_owners: 2,
+            report_dwells: true,
+            ..HeatmapOptions::default()
+        };
+        let mut builder = HeatmapBuilder::new(options).unwrap();
+        builder.set_owner(Some("alice"));
+        builder.add_file(&track([47.6, -122.3], 300));
+        builder.set_owner(Some("bob"));
+        builder.add_file(&track([47.6, -122.3], 200));
+        builder.add_file(b"not a track");
+        builder.add_polyline("_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+        builder
+    }
+
+    #[test]
+    fn round_trip() {
+        let builder = builder(OutputMode::Segments);
+        let saved = builder.save();
+        let loaded = HeatmapBuilder::load(&saved).unwrap();
+
+        assert_eq!(loaded.save(), saved);
+        assert_eq!(loaded.input_count(), builder.input_count());
+        assert_eq!(loaded.track_count(), builder.track_count());
+        assert_eq!(
+            serde_json::to_string(&loaded.snapshot()).unwrap(),
+            serde_json::to_string(&builder.snapshot()).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut saved = builder(OutputMode::Segments).save();
+        saved[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(HeatmapBuilder::load(&saved), Err(HeatmapError::Format(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let saved = builder(OutputMode::Segments).save();
+        for length in 0..saved.len() {
+            assert!(HeatmapBuilder::load(&saved[..length]).is_err(), "loaded {} of {} bytes", length, saved.len());
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut saved = builder(OutputMode::Segments).save();
+        saved.push(0);
+        assert!(matches!(HeatmapBuilder::load(&saved), Err(HeatmapError::Parse(_))));
+    }
+
+    #[test]
+    fn rejects_short_tracks() {
+        for points in 0..2 {
+            let mut builder = builder(OutputMode::SplitTracks);
+            builder.tracks[0].coordinates.truncate(points);
+            builder.tracks[0].times = None;
+            builder.tracks[0].elevations = None;
+            assert!(matches!(HeatmapBuilder::load(&builder.save()), Err(HeatmapError::Parse(_))));
+        }
+    }
+
+    // Every single-byte change must load or fail cleanly, and whatever loads
+    // must snapshot without panicking
+    #[test]
+    fn survives_corrupted_bytes() {
+        for output_mode in [OutputMode::Tracks, OutputMode::SplitTracks, OutputMode::Segments] {
+            let saved = builder(output_mode).save();
+            for i in 0..saved.len() {
+                for value in [0x00, 0x01, 0x7f, 0x80, 0xff, saved[i] ^ 0x01] {
+                    let mut corrupted = saved.clone();
+                    corrupted[i] = value;
+                    if let Ok(loaded) = HeatmapBuilder::load(&corrupted) {
+                        loaded.snapshot();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,485 @@
+// Privacy zones. Tracks are clipped exactly where they cross a zone's
+// boundary: each segment is cut at its crossings with every zone, the parts
+// inside a zone are dropped and the track is split there, so nothing inside a
+// zone reaches the output or the segment counts. Boundary points are rounded
+// to the output precision, stepping outward along the track when rounding
+// would land them inside, and take their elevation and time by interpolation.
+// Segments are counted between grid cell centers, and the line between two
+// centers can cross a zone even when the clipped track doesn't, so segments
+// whose line touches a zone are never counted.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{round, HeatmapOptions, InputDiagnostics, Track, METERS_PER_DEGREE};
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyZone {
+    // Everything within radius_m of a [lat, lon] center
+    Circle { center: [f64; 2], radius_m: f64 },
+    // A ring of [lat, lon] vertices, closed automatically
+    Polygon(Vec<[f64; 2]>),
+}
+
+impl PrivacyZone {
+    pub(crate) fn validate(&self) -> std::result::Result<(), &'static str> {
+        let valid = |&[lat, lon]: &[f64; 2]| (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon);
+        match self {
+            PrivacyZone::Circle { center, radius_m } => {
+                if !valid(center) {
+                    return Err("privacy zone centers must be valid [lat, lon] coordinates");
+                }
+                if !(radius_m.is_finite() && *radius_m > 0.0) {
+                    return Err("privacy zone radius_m must be a positive number");
+                }
+            }
+            PrivacyZone::Polygon(vertices) => {
+                if vertices.len() < 3 {
+                    return Err("privacy zone polygons need at least 3 vertices");
+                }
+                if !vertices.iter().all(valid) {
+                    return Err("privacy zone vertices must be valid [lat, lon] coordinates");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // [min lat, min lon, max lat, max lon]
+    fn bounds(&self) -> [f64; 4] {
+        match self {
+            PrivacyZone::Circle { center, radius_m } => {
+                let lat_reach = radius_m / METERS_PER_DEGREE;
+                let lon_reach = radius_m / (METERS_PER_DEGREE * center[0].to_radians().cos().max(0.01));
+                [center[0] - lat_reach, center[1] - lon_reach, center[0] + lat_reach, center[1] + lon_reach]
+            }
+            PrivacyZone::Polygon(vertices) => vertices.iter().fold(
+                [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY],
+                |[min_lat, min_lon, max_lat, max_lon], &[lat, lon]| {
+                    [min_lat.min(lat), min_lon.min(lon), max_lat.max(lat), max_lon.max(lon)]
+                },
+            ),
+        }
+    }
+
+    fn contains(&self, point: [f64; 2]) -> bool {
+        match self {
+            PrivacyZone::Circle { center, radius_m } => {
+                let [x, y] = meters_from(*center, point);
+                x * x + y * y <= radius_m * radius_m
+            }
+            // Even-odd ray casting along the longitude axis
+            PrivacyZone::Polygon(vertices) => {
+                let mut inside = false;
+                for (i, &a) in vertices.iter().enumerate() {
+                    let b = vertices[(i + 1) % vertices.len()];
+                    if (a[0] > point[0]) != (b[0] > point[0]) {
+                        let lon = a[1] + (point[0] - a[0]) / (b[0] - a[0]) * (b[1] - a[1]);
+                        if point[1] < lon {
+                            inside = !inside;
+                        }
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    // Fractions along start-end where the segment crosses the boundary
+    fn crossings(&self, start: [f64; 2], end: [f64; 2], found: &mut Vec<f64>) {
+        match self {
+            // Solve |start + t (end - start) - center| = radius in local meters
+            PrivacyZone::Circle { center, radius_m } => {
+                let a = meters_from(*center, start);
+                let b = meters_from(*center, end);
+                let d = [b[0] - a[0], b[1] - a[1]];
+                let qa = d[0] * d[0] + d[1] * d[1];
+                let qb = 2.0 * (a[0] * d[0] + a[1] * d[1]);
+                let qc = a[0] * a[0] + a[1] * a[1] - radius_m * radius_m;
+                let discriminant = qb * qb - 4.0 * qa * qc;
+                if qa > 0.0 && discriminant > 0.0 {
+                    let root = discriminant.sqrt();
+                    found.extend([(-qb - root) / (2.0 * qa), (-qb + root) / (2.0 * qa)]);
+                }
+            }
+            PrivacyZone::Polygon(vertices) => {
+                let d = [end[0] - start[0], end[1] - start[1]];
+                for (i, &a) in vertices.iter().enumerate() {
+                    let b = vertices[(i + 1) % vertices.len()];
+                    let e = [b[0] - a[0], b[1] - a[1]];
+                    let denominator = d[0] * e[1] - d[1] * e[0];
+                    if denominator == 0.0 {
+                        continue;
+                    }
+                    let w = [a[0] - start[0], a[1] - start[1]];
+                    let t = (w[0] * e[1] - w[1] * e[0]) / denominator;
+                    let u = (w[0] * d[1] - w[1] * d[0]) / denominator;
+                    if (0.0..=1.0).contains(&u) {
+                        found.push(t);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Whether the point is inside any of the zones
+pub(crate) fn in_any_zone(zones: &[PrivacyZone], point: [f64; 2]) -> bool {
+    zones.iter().any(|zone| zone.contains(point))
+}
+
+// Whether any part of the line from start to end is inside any of the zones
+pub(crate) fn line_in_any_zone(zones: &[PrivacyZone], start: [f64; 2], end: [f64; 2]) -> bool {
+    let mut crossings = Vec::new();
+    zones.iter().filter(|zone| overlaps(zone.bounds(), start, end)).any(|zone| {
+        crossings.clear();
+        zone.crossings(start, end, &mut crossings);
+        zone.contains(start) || zone.contains(end) || crossings.iter().any(|t| (0.0..=1.0).contains(t))
+    })
+}
+
+// [east, north] meters of a point from an origin
+fn meters_from(origin: [f64; 2], point: [f64; 2]) -> [f64; 2] {
+    let x_scale = METERS_PER_DEGREE * origin[0].to_radians().cos();
+    [(point[1] - origin[1]) * x_scale, (point[0] - origin[0]) * METERS_PER_DEGREE]
+}
+
+fn overlaps(bounds: [f64; 4], start: [f64; 2], end: [f64; 2]) -> bool {
+    start[0].max(end[0]) >= bounds[0]
+        && start[1].max(end[1]) >= bounds[1]
+        && start[0].min(end[0]) <= bounds[2]
+        && start[1].min(end[1]) <= bounds[3]
+}
+
+// The point a fraction t of the way from start to end, rounded to `precision`
+// decimals. While rounding leaves it inside a zone, t steps a rounding unit at
+// a time in the `outward` direction (-1 towards start, 1 towards end), whose
+// end of the segment is outside. Returns the point and its final t.
+fn boundary_point(
+    zones: &[PrivacyZone],
+    [start, end]: [[f64; 2]; 2],
+    mut t: f64,
+    outward: f64,
+    precision: u32,
+) -> ([f64; 2], f64) {
+    let span = (end[0] - start[0]).abs().max((end[1] - start[1]).abs());
+    let step = 10f64.powi(-(precision as i32)) / span;
+    loop {
+        let point = [
+            round(start[0] + (end[0] - start[0]) * t, precision),
+            round(start[1] + (end[1] - start[1]) * t, precision),
+        ];
+        if !in_any_zone(zones, point) || t <= 0.0 || t >= 1.0 {
+            return (point, t);
+        }
+        t = (t + outward * step).clamp(0.0, 1.0);
+    }
+}
+
+// The parts of the track outside every zone
+pub(crate) fn clip_to_zones(track: Track, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    let zones = &options.privacy_zones;
+    if zones.is_empty() {
+        return vec![track];
+    }
+    let bounds: Vec<[f64; 4]> = zones.iter().map(PrivacyZone::bounds).collect();
+    let coordinates = &track.coordinates;
+
+    // Zones whose bounds the segment from point i to point j touches
+    let nearby = |i: usize, j: usize| {
+        zones
+            .iter()
+            .zip(&bounds)
+            .filter(move |(_, &bounds)| overlaps(bounds, coordinates[i], coordinates[j]))
+            .map(|(zone, _)| zone)
+    };
+
+    let empty = || Track {
+        coordinates: Vec::new(),
+        elevations: track.elevations.as_ref().map(|_| Vec::new()),
+        times: track.times.as_ref().map(|_| Vec::new()),
+        activity: track.activity.clone(),
+    };
+    // Append the point a fraction t of the way from point i to point i + 1,
+    // with the zone on the side opposite `outward` when it's a boundary point
+    let push = |piece: &mut Track, i: usize, t: f64, outward: f64| {
+        let (point, t) = if t == 0.0 {
+            (coordinates[i], t)
+        } else {
+            let segment = [coordinates[i], coordinates[i + 1]];
+            boundary_point(zones, segment, t, outward, options.coordinate_precision)
+        };
+        let lerp = |series: &[f64]| if t == 0.0 { series[i] } else { series[i] + (series[i + 1] - series[i]) * t };
+        piece.coordinates.push(point);
+        if let (Some(elevations), Some(source)) = (&mut piece.elevations, &track.elevations) {
+            elevations.push(lerp(source));
+        }
+        if let (Some(times), Some(source)) = (&mut piece.times, &track.times) {
+            times.push(lerp(source));
+        }
+    };
+
+    let mut pieces = Vec::new();
+    let mut piece: Option<Track> = None;
+    if nearby(0, 0).any(|zone| zone.contains(coordinates[0])) {
+        diagnostics.hidden_points += 1;
+    } else {
+        let mut first = empty();
+        push(&mut first, 0, 0.0, 1.0);
+        piece = Some(first);
+    }
+
+    let mut cuts = Vec::new();
+    for i in 0..coordinates.len() - 1 {
+        let [start, end] = [coordinates[i], coordinates[i + 1]];
+        cuts.clear();
+        for zone in nearby(i, i + 1) {
+            zone.crossings(start, end, &mut cuts);
+        }
+        cuts.retain(|&t| t > 0.0 && t < 1.0);
+        cuts.sort_by(f64::total_cmp);
+        cuts.push(1.0);
+
+        // Each stretch between cuts is wholly inside or outside; test its middle
+        let mut from = 0.0;
+        let mut outside = piece.is_some();
+        for &to in &cuts {
+            let half = (from + to) / 2.0;
+            let middle = [start[0] + (end[0] - start[0]) * half, start[1] + (end[1] - start[1]) * half];
+            let stretch_outside = !nearby(i, i + 1).any(|zone| zone.contains(middle));
+            if stretch_outside && !outside {
+                let mut next = empty();
+                push(&mut next, i, from, 1.0);
+                piece = Some(next);
+            } else if !stretch_outside && outside {
+                if let Some(mut finished) = piece.take() {
+                    if from > 0.0 {
+                        push(&mut finished, i, from, -1.0);
+                    }
+                    pieces.push(finished);
+                }
+            }
+            outside = stretch_outside;
+            from = to;
+        }
+
+        match &mut piece {
+            Some(current) => push(current, i + 1, 0.0, 1.0),
+            None => diagnostics.hidden_points += 1,
+        }
+    }
+    pieces.extend(piece);
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+    use crate::{process_polyline_strings, OutputMode};
+
+    fn track(coordinates: Vec<[f64; 2]>) -> Track {
+        let count = coordinates.len();
+        Track {
+            coordinates,
+            elevations: Some((0..count).map(|i| 100.0 * (i + 1) as f64).collect()),
+            times: Some((0..count).map(|i| 100.0 * i as f64).collect()),
+            activity: None,
+        }
+    }
+
+    fn clip(track: Track, zone: PrivacyZone) -> (Vec<Track>, InputDiagnostics) {
+        let options = HeatmapOptions {
+            privacy_zones: vec![zone],
+            ..HeatmapOptions::default()
+        };
+        let mut diagnostics = InputDiagnostics::default();
+        let pieces = clip_to_zones(track, &options, &mut diagnostics);
+        (pieces, diagnostics)
+    }
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() <= tolerance
+    }
+
+    // A point the given meters east of HOME, along its latitude
+    fn east_of_home(meters: f64) -> [f64; 2] {
+        [HOME[0], HOME[1] + meters / (METERS_PER_DEGREE * HOME[0].to_radians().cos())]
+    }
+
+    const HOME: [f64; 2] = [47.6, -122.3];
+
+    fn home(radius_m: f64) -> PrivacyZone {
+        PrivacyZone::Circle {
+            center: HOME,
+            radius_m,
+        }
+    }
+
+    // Straight polyline through `points`, as the JSON accepted by process_polyline_strings
+    fn polyline(points: &[[f64; 2]]) -> String {
+        serde_json::to_string(points).unwrap()
+    }
+
+    // Every line drawn in each output mode, as runs of [lat, lon] vertices
+    fn output_lines(polylines: &[String], zone: &PrivacyZone) -> Vec<(OutputMode, Vec<[f64; 2]>)> {
+        let polylines: Vec<&str> = polylines.iter().map(String::as_str).collect();
+        let modes = [OutputMode::Tracks, OutputMode::SplitTracks, OutputMode::Segments, OutputMode::Network];
+        modes
+            .into_iter()
+            .flat_map(|output_mode| {
+                let options = HeatmapOptions {
+                    privacy_zones: vec![zone.clone()],
+                    output_mode,
+                    ..HeatmapOptions::default()
+                };
+                let result = process_polyline_strings(&polylines, &options).unwrap();
+                let mut lines: Vec<Vec<[f64; 2]>> = result.tracks.into_iter().map(|track| track.coordinates).collect();
+                if let Some(network) = result.network {
+                    lines.extend(network.edges.into_iter().map(|edge| edge.coordinates));
+                }
+                assert!(!lines.is_empty(), "no {:?} output", output_mode);
+                lines.into_iter().map(move |line| (output_mode, line))
+            })
+            .collect()
+    }
+
+    fn assert_outside(polylines: &[String], zone: PrivacyZone) {
+        let zones = [zone];
+        for (output_mode, line) in output_lines(polylines, &zones[0]) {
+            for window in line.windows(2) {
+                assert!(
+                    !line_in_any_zone(&zones, window[0], window[1]),
+                    "{:?} line {:?} reaches into the zone",
+                    output_mode,
+                    window
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn output_stays_out_of_zones() {
+        // North-south through the middle of the zone, densely sampled
+        let points: Vec<[f64; 2]> = (0..=100).map(|i| [47.595 + i as f64 * 0.0001, -122.3]).collect();
+        assert_outside(&[polyline(&points)], home(150.0));
+    }
+
+    #[test]
+    fn boundary_points_stay_out_of_zones() {
+        // Straight tracks across the zone at many angles and offsets, so the
+        // boundary points land all around it
+        let tracks: Vec<String> = (0..40)
+            .map(|i| {
+                let angle = i as f64 * 0.37;
+                let offset = (i % 7) as f64 * 30.0 - 90.0;
+                let along = [angle.cos(), angle.sin()];
+                let point = |distance: f64| {
+                    let [north, east] = [along[0] * distance - along[1] * offset, along[1] * distance + along[0] * offset];
+                    [HOME[0] + north / METERS_PER_DEGREE, east_of_home(east)[1]]
+                };
+                polyline(&[point(-800.0), point(800.0)])
+            })
+            .collect();
+        let zone = home(200.0);
+        for (output_mode, line) in output_lines(&tracks, &zone) {
+            assert!(line.iter().all(|&point| !zone.contains(point)), "{:?} {:?}", output_mode, line);
+        }
+        assert_outside(&tracks, zone);
+    }
+
+    #[test]
+    fn segments_skirting_a_small_zone_stay_out_of_it() {
+        // A 5 m zone between two grid cell centers, both outside it, and a
+        // straight road passing 8 m from it through the same cells
+        let grid = Grid::new(HeatmapOptions::default().grid_size_m);
+        let cell = grid.cell(HOME);
+        let [south, north] = [grid.center(cell), grid.center([cell[0] + 1, cell[1]])];
+        let center = [(south[0] + north[0]) / 2.0, south[1]];
+        let zone = PrivacyZone::Circle { center, radius_m: 5.0 };
+        let lon = center[1] + 8.0 / (METERS_PER_DEGREE * center[0].to_radians().cos());
+        let points: Vec<[f64; 2]> = (0..=40).map(|i| [center[0] - 0.002 + i as f64 * 0.0001, lon]).collect();
+        assert_outside(&[polyline(&points)], zone);
+    }
+
+    #[test]
+    fn circle_entered_and_left_within_one_segment() {
+        let (pieces, diagnostics) = clip(track(vec![east_of_home(-750.0), east_of_home(750.0)]), home(300.0));
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(diagnostics.hidden_points, 0);
+
+        // Cut 300 m either side of the center, 0.3 and 0.7 of the way along
+        let [before, after] = [&pieces[0], &pieces[1]];
+        assert_eq!(before.coordinates[0], east_of_home(-750.0));
+        assert_eq!(after.coordinates[1], east_of_home(750.0));
+        for (boundary, expected) in [(before.coordinates[1], -300.0), (after.coordinates[0], 300.0)] {
+            assert!(close(meters_from(HOME, boundary)[0], expected, 1.0), "{:?}", boundary);
+            assert!(close(meters_from(HOME, boundary)[1], 0.0, 1e-6));
+        }
+        assert!(close(before.times.as_ref().unwrap()[1], 30.0, 1e-6));
+        assert!(close(after.times.as_ref().unwrap()[0], 70.0, 1e-6));
+        assert!(close(before.elevations.as_ref().unwrap()[1], 130.0, 1e-6));
+        assert!(close(after.elevations.as_ref().unwrap()[0], 170.0, 1e-6));
+    }
+
+    #[test]
+    fn polygon_entered_and_left_within_one_segment() {
+        // A square reaching 0.002 degrees either side of HOME
+        let [lat, lon] = HOME;
+        let square = PrivacyZone::Polygon(vec![
+            [lat - 0.002, lon - 0.002],
+            [lat - 0.002, lon + 0.002],
+            [lat + 0.002, lon + 0.002],
+            [lat + 0.002, lon - 0.002],
+        ]);
+        let (pieces, diagnostics) = clip(track(vec![[lat, lon - 0.01], [lat, lon + 0.01]]), square.clone());
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(diagnostics.hidden_points, 0);
+
+        // Cut at the edges, within a rounding step, on the outside
+        let [before, after] = [&pieces[0], &pieces[1]];
+        assert_eq!([before.coordinates[0], after.coordinates[1]], [[lat, lon - 0.01], [lat, lon + 0.01]]);
+        for (boundary, expected) in [(before.coordinates[1], lon - 0.002), (after.coordinates[0], lon + 0.002)] {
+            assert_eq!(boundary[0], lat);
+            assert!(close(boundary[1], expected, 1.01e-5), "{:?}", boundary);
+            assert!(!square.contains(boundary), "{:?}", boundary);
+        }
+        assert!(close(before.times.as_ref().unwrap()[1], 40.0, 0.1));
+        assert!(close(after.times.as_ref().unwrap()[0], 60.0, 0.1));
+        assert!(close(before.elevations.as_ref().unwrap()[1], 140.0, 0.1));
+        assert!(close(after.elevations.as_ref().unwrap()[0], 160.0, 0.1));
+    }
+
+    #[test]
+    fn track_starting_inside_a_zone() {
+        let points = vec![HOME, east_of_home(600.0), east_of_home(900.0)];
+        let (pieces, diagnostics) = clip(track(points), home(300.0));
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(diagnostics.hidden_points, 1);
+
+        // Starts where the first segment leaves the zone, halfway along it
+        let piece = &pieces[0];
+        assert_eq!(piece.coordinates[1..], [east_of_home(600.0), east_of_home(900.0)]);
+        assert!(close(meters_from(HOME, piece.coordinates[0])[0], 300.0, 1.0));
+        assert!(close(piece.times.as_ref().unwrap()[0], 50.0, 1e-6));
+        assert_eq!(piece.times.as_ref().unwrap()[1..], [100.0, 200.0]);
+        assert!(close(piece.elevations.as_ref().unwrap()[0], 150.0, 1e-6));
+    }
+
+    #[test]
+    fn counts_hidden_points() {
+        let points = [-900.0, -200.0, -50.0, 0.0, 250.0, 900.0].map(east_of_home).to_vec();
+        let (pieces, diagnostics) = clip(track(points), home(300.0));
+        assert_eq!(diagnostics.hidden_points, 4);
+        assert_eq!(pieces.iter().map(Track::len).collect::<Vec<_>>(), [2, 2]);
+    }
+
+    #[test]
+    fn track_outside_every_zone_is_unchanged() {
+        let points = vec![east_of_home(400.0), east_of_home(800.0), east_of_home(1200.0)];
+        let (pieces, diagnostics) = clip(track(points.clone()), home(300.0));
+        assert_eq!(diagnostics.hidden_points, 0);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].coordinates, points);
+    }
+}
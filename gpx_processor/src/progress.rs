@@ -0,0 +1,51 @@
+// Progress reporting and cooperative cancellation for long batch runs
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+// Pipeline stage an input is about to enter
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    #[default]
+    Parse,
+    // Jump filtering and simplification
+    Filter,
+    // Counting segment usage
+    Aggregate,
+}
+
+// Reported before each phase of each input, and once more when everything is done
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct Progress {
+    pub phase: Phase,
+    pub files_done: usize,
+    pub files_total: usize,
+    // Raw points read so far, including the input in progress
+    pub points: usize,
+    pub bytes_done: usize,
+    pub bytes_total: usize,
+}
+
+// Cheap to clone; every clone shares the same flag, so one can be handed to
+// another thread and cancelled from there. Checked between phases.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,120 @@
+// RideWithGPS API input: trip and route JSON, with full-fidelity track_points
+// https://ridewithgps.com/api/v1/doc
+
+use serde::Deserialize;
+
+use crate::{decode_polyline_raw, ActivityMetadata, ActivitySource, HeatmapError, InputDiagnostics, Track};
+
+// A trip or route as returned by the API, either bare or wrapped in
+// {"trip": ...} / {"route": ...} like the detail endpoints do
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum RwgpsDocument {
+    Trip { trip: RwgpsTrip },
+    Route { route: RwgpsTrip },
+    Bare(RwgpsTrip),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RwgpsTrip {
+    #[serde(default)]
+    pub id: Option<u64>,
+    // The owner for `min_owners`
+    #[serde(default)]
+    pub user_id: Option<u64>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub activity_type: Option<String>,
+    // Trips only; routes have no departure time
+    #[serde(default)]
+    pub departed_at: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    // Meters
+    #[serde(default)]
+    pub distance: Option<f64>,
+    #[serde(default)]
+    pub track_points: Vec<RwgpsTrackPoint>,
+    #[serde(default)]
+    pub track_encoded: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RwgpsTrackPoint {
+    // Longitude
+    #[serde(default)]
+    pub x: Option<f64>,
+    // Latitude
+    #[serde(default)]
+    pub y: Option<f64>,
+    // Elevation in meters
+    #[serde(default)]
+    pub e: Option<f64>,
+    // Unix seconds
+    #[serde(default)]
+    pub t: Option<f64>,
+}
+
+impl RwgpsDocument {
+    pub fn into_trip(self) -> RwgpsTrip {
+        match self {
+            RwgpsDocument::Trip { trip } => trip,
+            RwgpsDocument::Route { route } => route,
+            RwgpsDocument::Bare(trip) => trip,
+        }
+    }
+}
+
+impl RwgpsTrip {
+    pub(crate) fn owner(&self) -> Option<String> {
+        self.user_id.map(|id| format!("ridewithgps:{}", id))
+    }
+
+    fn metadata(&self) -> ActivityMetadata {
+        ActivityMetadata {
+            source: ActivitySource::Ridewithgps,
+            id: self.id,
+            name: self.name.clone(),
+            sport_type: self.activity_type.clone(),
+            start_date: self.departed_at.clone().or_else(|| self.created_at.clone()),
+            distance: self.distance,
+        }
+    }
+
+    // Convert to a pipeline track. Elevations and times are kept only when every
+    // located point has them, so they stay aligned with the coordinates.
+    pub(crate) fn into_track(self, diagnostics: &mut InputDiagnostics) -> Option<Track> {
+        let (mut track, read) = if !self.track_points.is_empty() {
+            let located: Vec<&RwgpsTrackPoint> = self
+                .track_points
+                .iter()
+                .filter(|p| p.x.is_some() && p.y.is_some())
+                .collect();
+
+            let coordinates: Vec<[f64; 2]> = located
+                .iter()
+                .map(|p| [p.y.unwrap_or_default(), p.x.unwrap_or_default()])
+                .collect();
+            let elevations: Option<Vec<f64>> = located.iter().map(|p| p.e).collect();
+            let times: Option<Vec<f64>> = located.iter().map(|p| p.t).collect();
+
+            // Points without a position count as invalid
+            let track = Track::from_points(&coordinates, elevations.as_deref(), times.as_deref());
+            (track, self.track_points.len())
+        } else {
+            let Some(encoded) = self.track_encoded.as_deref().filter(|p| !p.is_empty()) else {
+                diagnostics.error(HeatmapError::Format(
+                    "trip has no track_points or track_encoded".to_string(),
+                ));
+                return None;
+            };
+            let coords = decode_polyline_raw(encoded);
+            (Track::from_points(&coords, None, None), coords.len())
+        };
+        diagnostics.read_points(read, track.len());
+
+        track.activity = Some(self.metadata());
+        Some(track)
+    }
+}
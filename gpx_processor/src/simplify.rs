@@ -0,0 +1,208 @@
+// Line simplification. Every mode returns the ascending indices of the points
+// to keep, always including the first and last, and takes its tolerance in
+// meters. Douglas-Peucker and Visvalingam-Whyatt work on the track projected
+// to local meters around its first point, which is accurate at track scale.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{simplify_track, METERS_PER_DEGREE};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SimplifyMode {
+    // Drop points within the tolerance of the last kept point
+    #[default]
+    Radius,
+    // Ramer-Douglas-Peucker: keep points further than the tolerance from the simplified line
+    DouglasPeucker,
+    // Visvalingam-Whyatt: drop points whose triangle with their neighbours is
+    // smaller than tolerance² (a tolerance-sized bump spanning twice the tolerance)
+    Visvalingam,
+}
+
+pub(crate) fn simplify(points: &[[f64; 2]], mode: SimplifyMode, tolerance: f64) -> Vec<usize> {
+    if points.len() <= 2 {
+        return (0..points.len()).collect();
+    }
+    match mode {
+        SimplifyMode::Radius => simplify_track(points, tolerance),
+        SimplifyMode::DouglasPeucker => douglas_peucker(&project(points), tolerance),
+        SimplifyMode::Visvalingam => visvalingam(&project(points), tolerance * tolerance),
+    }
+}
+
+// [x, y] in meters east and north of the first point
+fn project(points: &[[f64; 2]]) -> Vec<[f64; 2]> {
+    let [lat0, lon0] = points[0];
+    let x_scale = METERS_PER_DEGREE * lat0.to_radians().cos();
+    points
+        .iter()
+        .map(|[lat, lon]| [(lon - lon0) * x_scale, (lat - lat0) * METERS_PER_DEGREE])
+        .collect()
+}
+
+fn douglas_peucker(points: &[[f64; 2]], tolerance: f64) -> Vec<usize> {
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Explicit stack so long tracks can't overflow the call stack
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = ranges.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| (i, segment_distance(points[i], points[first], points[last])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((index, distance)) = farthest {
+            if distance > tolerance {
+                keep[index] = true;
+                ranges.push((first, index));
+                ranges.push((index, last));
+            }
+        }
+    }
+
+    (0..points.len()).filter(|&i| keep[i]).collect()
+}
+
+// Distance from p to the segment a-b
+fn segment_distance(p: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let length_squared = dx * dx + dy * dy;
+    let t = if length_squared > 0.0 {
+        (((p[0] - a[0]) * dx + (p[1] - a[1]) * dy) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (x, y) = (a[0] + t * dx - p[0], a[1] + t * dy - p[1]);
+    (x * x + y * y).sqrt()
+}
+
+fn triangle_area(a: [f64; 2], b: [f64; 2], c: [f64; 2]) -> f64 {
+    ((b[0] - a[0]) * (c[1] - a[1]) - (c[0] - a[0]) * (b[1] - a[1])).abs() / 2.0
+}
+
+// Min-heap entry; stale entries are skipped by comparing against the current area
+struct Candidate {
+    area: f64,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    // Reversed so BinaryHeap pops the smallest area, ties by lowest index
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.area.total_cmp(&self.area).then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+fn visvalingam(points: &[[f64; 2]], min_area: f64) -> Vec<usize> {
+    let n = points.len();
+    let mut previous: Vec<usize> = (0..n).map(|i| i.saturating_sub(1)).collect();
+    let mut next: Vec<usize> = (0..n).map(|i| (i + 1).min(n - 1)).collect();
+    let mut area = vec![f64::INFINITY; n];
+    let mut removed = vec![false; n];
+
+    let mut heap = BinaryHeap::new();
+    for i in 1..n - 1 {
+        area[i] = triangle_area(points[i - 1], points[i], points[i + 1]);
+        heap.push(Candidate { area: area[i], index: i });
+    }
+
+    let mut last_removed_area: f64 = 0.0;
+    while let Some(Candidate { area: candidate_area, index }) = heap.pop() {
+        if removed[index] || candidate_area != area[index] {
+            continue;
+        }
+        if candidate_area >= min_area {
+            break;
+        }
+        removed[index] = true;
+        last_removed_area = last_removed_area.max(candidate_area);
+
+        let (before, after) = (previous[index], next[index]);
+        next[before] = after;
+        previous[after] = before;
+
+        // Neighbours never drop below the area already removed, so points are
+        // eliminated in a consistent order
+        for neighbour in [before, after] {
+            if neighbour != 0 && neighbour != n - 1 {
+                let updated = triangle_area(points[previous[neighbour]], points[neighbour], points[next[neighbour]]);
+                area[neighbour] = updated.max(last_removed_area);
+                heap.push(Candidate {
+                    area: area[neighbour],
+                    index: neighbour,
+                });
+            }
+        }
+    }
+
+    (0..n).filter(|&i| !removed[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [SimplifyMode; 2] = [SimplifyMode::DouglasPeucker, SimplifyMode::Visvalingam];
+
+    // [lat, lon] from meters east and north of a point in Seattle
+    fn point(east: f64, north: f64) -> [f64; 2] {
+        let lat0: f64 = 47.6;
+        [lat0 + north / METERS_PER_DEGREE, -122.3 + east / (METERS_PER_DEGREE * lat0.to_radians().cos())]
+    }
+
+    #[test]
+    fn hairpin_keeps_its_apex() {
+        // 500 m up, a tight turn, and 500 m back down 20 m to the east
+        let mut track: Vec<[f64; 2]> = (0..=50).map(|i| point(0.0, i as f64 * 10.0)).collect();
+        track.extend((0..=50).rev().map(|i| point(20.0, i as f64 * 10.0)));
+        for mode in MODES {
+            let keep = simplify(&track, mode, 5.0);
+            assert_eq!(keep, vec![0, 50, 51, 101], "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn drops_collinear_points() {
+        let track: Vec<[f64; 2]> = (0..=100).map(|i| point(i as f64 * 7.0, i as f64 * 3.0)).collect();
+        for mode in MODES {
+            assert_eq!(simplify(&track, mode, 1.0), vec![0, 100], "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn always_keeps_endpoints() {
+        // A small wiggle, well within the tolerance, ending back near the start
+        let track: Vec<[f64; 2]> = (0..20).map(|i| point((i % 3) as f64, (i % 2) as f64)).collect();
+        for mode in MODES {
+            let keep = simplify(&track, mode, 50.0);
+            assert_eq!(keep.first(), Some(&0), "{mode:?}");
+            assert_eq!(keep.last(), Some(&19), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn zero_tolerance_keeps_every_point() {
+        let track: Vec<[f64; 2]> = (0..30).map(|i| point(i as f64 * 10.0, (i % 2) as f64 * 0.5)).collect();
+        for mode in MODES {
+            assert_eq!(simplify(&track, mode, 0.0), (0..30).collect::<Vec<_>>(), "{mode:?}");
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// Track smoothing, run just before simplification. Phone recordings zig-zag a
+// few meters either side of the road, which widens the heat band and spreads
+// one road over neighbouring grid cells. With timestamps, a constant-velocity
+// Kalman filter followed by a Rauch-Tung-Striebel pass gives the most likely
+// path; without them, a window of neighbouring points is averaged or fitted
+// with a Savitzky-Golay quadratic. The windowed filters keep both endpoints.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{round, HeatmapOptions, Track, METERS_PER_DEGREE};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmoothingMode {
+    #[default]
+    Off,
+    // Kalman for tracks with timestamps, Savitzky-Golay for the rest
+    Auto,
+    // Only tracks with timestamps are smoothed
+    Kalman,
+    MovingAverage,
+    SavitzkyGolay,
+}
+
+pub(crate) fn smooth(mut track: Track, options: &HeatmapOptions) -> Track {
+    if track.len() < 3 {
+        return track;
+    }
+
+    let smoothed = match (options.smoothing, track.times.as_deref()) {
+        (SmoothingMode::Off, _) | (SmoothingMode::Kalman, None) => return track,
+        (SmoothingMode::Auto | SmoothingMode::Kalman, Some(times)) => kalman(&track.coordinates, times, options),
+        (SmoothingMode::MovingAverage, _) => windowed(&track.coordinates, options.smoothing_window, moving_average),
+        (SmoothingMode::Auto | SmoothingMode::SavitzkyGolay, _) => {
+            windowed(&track.coordinates, options.smoothing_window, savitzky_golay)
+        }
+    };
+
+    let precision = options.coordinate_precision;
+    track.coordinates = smoothed
+        .into_iter()
+        .map(|[lat, lon]| [round(lat, precision), round(lon, precision)])
+        .collect();
+    track
+}
+
+// Apply `filter` to each point with a centered window of up to `window` points,
+// narrowed near the ends so it stays centered
+fn windowed(points: &[[f64; 2]], window: usize, filter: fn(&[[f64; 2]]) -> [f64; 2]) -> Vec<[f64; 2]> {
+    let half = window / 2;
+    (0..points.len())
+        .map(|i| {
+            let reach = half.min(i).min(points.len() - 1 - i);
+            filter(&points[i - reach..=i + reach])
+        })
+        .collect()
+}
+
+fn moving_average(window: &[[f64; 2]]) -> [f64; 2] {
+    let count = window.len() as f64;
+    let [lat, lon] = window.iter().fold([0.0, 0.0], |sum, point| [sum[0] + point[0], sum[1] + point[1]]);
+    [lat / count, lon / count]
+}
+
+// Value at the center of a least-squares quadratic through the window, which
+// follows curves better than an average. Three points or fewer fit exactly.
+fn savitzky_golay(window: &[[f64; 2]]) -> [f64; 2] {
+    let half = (window.len() / 2) as f64;
+    let norm = (2.0 * half + 1.0) * (4.0 * half * half + 4.0 * half - 3.0);
+    let mut smoothed = [0.0, 0.0];
+    for (i, point) in window.iter().enumerate() {
+        let offset = i as f64 - half;
+        let weight = (3.0 * (3.0 * half * half + 3.0 * half - 1.0) - 15.0 * offset * offset) / norm;
+        smoothed[0] += weight * point[0];
+        smoothed[1] += weight * point[1];
+    }
+    smoothed
+}
+
+type Matrix = [[f64; 2]; 2];
+
+fn multiply(a: Matrix, b: Matrix) -> Matrix {
+    let cell = |row: usize, column: usize| a[row][0] * b[0][column] + a[row][1] * b[1][column];
+    [[cell(0, 0), cell(0, 1)], [cell(1, 0), cell(1, 1)]]
+}
+
+fn transpose(a: Matrix) -> Matrix {
+    [[a[0][0], a[1][0]], [a[0][1], a[1][1]]]
+}
+
+fn inverse(a: Matrix) -> Matrix {
+    let determinant = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+    [[a[1][1] / determinant, -a[0][1] / determinant], [-a[1][0] / determinant, a[0][0] / determinant]]
+}
+
+// Smooth in local meters around the first point, each axis on its own
+fn kalman(points: &[[f64; 2]], times: &[f64], options: &HeatmapOptions) -> Vec<[f64; 2]> {
+    let [lat0, lon0] = points[0];
+    let x_scale = METERS_PER_DEGREE * lat0.to_radians().cos().max(0.01);
+
+    let east: Vec<f64> = points.iter().map(|point| (point[1] - lon0) * x_scale).collect();
+    let north: Vec<f64> = points.iter().map(|point| (point[0] - lat0) * METERS_PER_DEGREE).collect();
+    let east = kalman_axis(&east, times, options);
+    let north = kalman_axis(&north, times, options);
+
+    east.iter()
+        .zip(&north)
+        .map(|(x, y)| [lat0 + y / METERS_PER_DEGREE, lon0 + x / x_scale])
+        .collect()
+}
+
+// Position and velocity along one axis, measured in position only
+fn kalman_axis(measured: &[f64], times: &[f64], options: &HeatmapOptions) -> Vec<f64> {
+    let measurement_variance = options.kalman_measurement_noise_m.powi(2);
+    let acceleration_variance = options.kalman_acceleration_noise_ms2.powi(2);
+
+    // Forward pass, keeping the predicted and filtered estimates for smoothing
+    let mut predicted: Vec<([f64; 2], Matrix)> = Vec::with_capacity(measured.len());
+    let mut filtered: Vec<([f64; 2], Matrix)> = Vec::with_capacity(measured.len());
+    let mut transitions: Vec<Matrix> = Vec::with_capacity(measured.len());
+
+    // Unknown starting speed: allow anything up to ~30 m/s
+    let mut state = [measured[0], 0.0];
+    let mut covariance = [[measurement_variance, 0.0], [0.0, 900.0]];
+    for (i, &position) in measured.iter().enumerate() {
+        if i > 0 {
+            // Duplicate or out-of-order times predict no movement
+            let dt = (times[i] - times[i - 1]).max(0.0);
+            let transition = [[1.0, dt], [0.0, 1.0]];
+            let noise = [
+                [dt.powi(4) / 4.0, dt.powi(3) / 2.0],
+                [dt.powi(3) / 2.0, dt * dt],
+            ]
+            .map(|row| row.map(|value| value * acceleration_variance));
+
+            state = [state[0] + dt * state[1], state[1]];
+            covariance = multiply(multiply(transition, covariance), transpose(transition));
+            for (row, noise_row) in covariance.iter_mut().zip(noise) {
+                row[0] += noise_row[0];
+                row[1] += noise_row[1];
+            }
+            transitions.push(transition);
+        }
+        predicted.push((state, covariance));
+
+        let innovation_variance = covariance[0][0] + measurement_variance;
+        let gain = [covariance[0][0] / innovation_variance, covariance[1][0] / innovation_variance];
+        let innovation = position - state[0];
+        state = [state[0] + gain[0] * innovation, state[1] + gain[1] * innovation];
+        covariance = [
+            [(1.0 - gain[0]) * covariance[0][0], (1.0 - gain[0]) * covariance[0][1]],
+            [covariance[1][0] - gain[1] * covariance[0][0], covariance[1][1] - gain[1] * covariance[0][1]],
+        ];
+        filtered.push((state, covariance));
+    }
+
+    // Backward Rauch-Tung-Striebel pass, so each point also uses the ones after it
+    let mut smoothed = vec![0.0; measured.len()];
+    let mut next = filtered[measured.len() - 1].0;
+    smoothed[measured.len() - 1] = next[0];
+    for i in (0..measured.len() - 1).rev() {
+        let (state, covariance) = filtered[i];
+        let (next_predicted, next_covariance) = predicted[i + 1];
+        let gain = multiply(multiply(covariance, transpose(transitions[i])), inverse(next_covariance));
+        let difference = [next[0] - next_predicted[0], next[1] - next_predicted[1]];
+        next = [
+            state[0] + gain[0][0] * difference[0] + gain[0][1] * difference[1],
+            state[1] + gain[1][0] * difference[0] + gain[1][1] * difference[1],
+        ];
+        smoothed[i] = next[0];
+    }
+    smoothed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance;
+
+    const MODES: [SmoothingMode; 3] = [SmoothingMode::Kalman, SmoothingMode::MovingAverage, SmoothingMode::SavitzkyGolay];
+
+    // 300 points heading east at ~4 m/s, one a second, each moved up to
+    // `noise_m` north or south of the line
+    fn line(noise_m: f64) -> Track {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let coordinates: Vec<[f64; 2]> = (0..300)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let offset = ((state >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0) * noise_m;
+                [47.6 + offset / METERS_PER_DEGREE, -122.3 + i as f64 * 0.00005]
+            })
+            .collect();
+        let times: Vec<f64> = (0..300).map(|i| 1_714_550_400.0 + i as f64).collect();
+        Track::from_points(&coordinates, None, Some(&times))
+    }
+
+    fn options(smoothing: SmoothingMode) -> HeatmapOptions {
+        HeatmapOptions {
+            smoothing,
+            ..HeatmapOptions::default()
+        }
+    }
+
+    // Root mean square distance in meters from the true line
+    fn error(track: &Track) -> f64 {
+        let sum: f64 = track.coordinates.iter().map(|point| ((point[0] - 47.6) * METERS_PER_DEGREE).powi(2)).sum();
+        (sum / track.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn noisy_line_moves_closer_to_the_truth() {
+        for mode in MODES {
+            let noisy = line(5.0);
+            let before = error(&noisy);
+            let after = error(&smooth(noisy, &options(mode)));
+            assert!(after < before * 0.7, "{mode:?}: {before} m before, {after} m after");
+        }
+    }
+
+    #[test]
+    fn straight_line_passes_through() {
+        for mode in MODES {
+            let straight = line(0.0);
+            let smoothed = smooth(line(0.0), &options(mode));
+            assert_eq!(smoothed.len(), straight.len());
+            for (a, b) in straight.coordinates.iter().zip(&smoothed.coordinates) {
+                assert!(distance(*a, *b) < 0.5, "{mode:?}: {a:?} moved to {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_timestamps_stay_finite() {
+        let mut track = line(5.0);
+        // Pairs of points logged in the same second, then a run all at once
+        for (i, time) in track.times.as_mut().unwrap().iter_mut().enumerate() {
+            *time = if i < 200 { (i / 2) as f64 } else { 100.0 };
+        }
+        let smoothed = smooth(track, &options(SmoothingMode::Kalman));
+        assert!(smoothed.coordinates.iter().flatten().all(|value| value.is_finite()));
+    }
+}
@@ -0,0 +1,160 @@
+// Strava API input: SummaryActivity objects, optionally with their streams attached
+// https://developers.strava.com/docs/reference/#api-models-SummaryActivity
+// https://developers.strava.com/docs/reference/#api-models-StreamSet
+
+use serde::Deserialize;
+
+use crate::{
+    decode_polyline_raw, parse_timestamp, ActivityMetadata, ActivitySource, HeatmapError,
+    InputDiagnostics, Track,
+};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StravaActivity {
+    pub id: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub sport_type: Option<String>,
+    // Deprecated by Strava in favour of sport_type, but still returned
+    #[serde(default, rename = "type")]
+    pub activity_type: Option<String>,
+    #[serde(default)]
+    pub start_date: Option<String>,
+    // Meters
+    #[serde(default)]
+    pub distance: Option<f64>,
+    #[serde(default)]
+    pub map: Option<StravaMap>,
+    // Streams fetched from /activities/{id}/streams, attached by the caller
+    #[serde(default)]
+    pub streams: Option<StravaStreams>,
+    // The athlete's ID is the activity's owner for `min_owners`
+    #[serde(default)]
+    pub athlete: Option<StravaAthlete>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StravaAthlete {
+    pub id: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StravaMap {
+    #[serde(default)]
+    pub polyline: Option<String>,
+    #[serde(default)]
+    pub summary_polyline: Option<String>,
+}
+
+// Both shapes the streams endpoint returns: a list of typed streams, or an
+// object keyed by stream type when requested with key_by_type=true
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum StravaStreams {
+    List(Vec<TypedStream>),
+    Keyed(StreamSet),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TypedStream {
+    Latlng { data: Vec<[f64; 2]> },
+    Time { data: Vec<f64> },
+    Altitude { data: Vec<f64> },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct StreamSet {
+    #[serde(default)]
+    pub latlng: Option<Stream<[f64; 2]>>,
+    // Seconds since the start of the activity
+    #[serde(default)]
+    pub time: Option<Stream<f64>>,
+    // Meters
+    #[serde(default)]
+    pub altitude: Option<Stream<f64>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Stream<T> {
+    pub data: Vec<T>,
+}
+
+impl StravaStreams {
+    fn into_set(self) -> StreamSet {
+        match self {
+            StravaStreams::Keyed(set) => set,
+            StravaStreams::List(streams) => {
+                let mut set = StreamSet::default();
+                for stream in streams {
+                    match stream {
+                        TypedStream::Latlng { data } => set.latlng = Some(Stream { data }),
+                        TypedStream::Time { data } => set.time = Some(Stream { data }),
+                        TypedStream::Altitude { data } => set.altitude = Some(Stream { data }),
+                        TypedStream::Other => {}
+                    }
+                }
+                set
+            }
+        }
+    }
+}
+
+impl StravaActivity {
+    pub(crate) fn owner(&self) -> Option<String> {
+        self.athlete.as_ref().map(|athlete| format!("strava:{}", athlete.id))
+    }
+
+    fn metadata(&self) -> ActivityMetadata {
+        ActivityMetadata {
+            source: ActivitySource::Strava,
+            id: Some(self.id),
+            name: self.name.clone(),
+            sport_type: self.sport_type.clone().or_else(|| self.activity_type.clone()),
+            start_date: self.start_date.clone(),
+            distance: self.distance,
+        }
+    }
+
+    // Convert to a pipeline track, preferring streams over the detailed polyline
+    // over the summary polyline
+    pub(crate) fn into_track(mut self, diagnostics: &mut InputDiagnostics) -> Option<Track> {
+        let (mut track, read) = match self.streams.take().map(StravaStreams::into_set) {
+            Some(StreamSet { latlng: Some(latlng), time, altitude }) => {
+                // Stream times are offsets from the start; make them absolute when we can
+                let start = self.start_date.as_deref().and_then(parse_timestamp).unwrap_or(0.0);
+                let times = time.map(|t| t.data.iter().map(|offset| start + offset).collect::<Vec<_>>());
+                let track = Track::from_points(
+                    &latlng.data,
+                    altitude.as_ref().map(|a| a.data.as_slice()),
+                    times.as_deref(),
+                );
+                (track, latlng.data.len())
+            }
+            _ => {
+                let polyline = self.map.as_ref().and_then(|map| {
+                    map.polyline
+                        .as_deref()
+                        .filter(|p| !p.is_empty())
+                        .or(map.summary_polyline.as_deref())
+                });
+                let Some(polyline) = polyline else {
+                    diagnostics.error(HeatmapError::Format(format!(
+                        "activity {} has no latlng stream or polyline",
+                        self.id
+                    )));
+                    return None;
+                };
+                let coords = decode_polyline_raw(polyline);
+                (Track::from_points(&coords, None, None), coords.len())
+            }
+        };
+        diagnostics.read_points(read, track.len());
+
+        track.activity = Some(self.metadata());
+        Some(track)
+    }
+}
@@ -0,0 +1,33 @@
+// GPX fixtures shared by the unit tests. Points are timed in seconds after
+// 08:00 UTC on 2024-05-01, so tracks of any length get valid timestamps.
+
+// A GPX file with one track, each segment a list of (seconds, [lat, lon])
+pub(crate) fn gpx(segments: &[Vec<(usize, [f64; 2])>]) -> Vec<u8> {
+    let mut gpx = String::from(r#"<?xml version="1.0"?><gpx version="1.1" creator="test"><trk>"#);
+    for segment in segments {
+        gpx += "<trkseg>";
+        for &(second, [lat, lon]) in segment {
+            gpx += &format!(r#"<trkpt lat="{lat:.6}" lon="{lon:.6}"><time>{}</time></trkpt>"#, timestamp(second));
+        }
+        gpx += "</trkseg>";
+    }
+    gpx += "</trk></gpx>";
+    gpx.into_bytes()
+}
+
+// A GPX file with a single segment recorded at 1 Hz
+pub(crate) fn gpx_1hz(points: impl IntoIterator<Item = [f64; 2]>) -> Vec<u8> {
+    gpx(&[points.into_iter().enumerate().collect()])
+}
+
+fn timestamp(second: usize) -> String {
+    let seconds = 8 * 3600 + second;
+    let day = 1 + seconds / 86_400;
+    assert!(day <= 31, "fixture runs past the end of May");
+    format!(
+        "2024-05-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,163 @@
+// Start and end trimming, like Strava's "hide start/end". The first and last
+// trim_m meters of every activity are removed, plus a per-activity jitter of up
+// to trim_jitter_m so the cut doesn't sit at a fixed distance from the true
+// endpoint. The jitter is drawn from a SplitMix64 generator seeded with
+// trim_seed and every raw point of the activity: reprocessing gives the same
+// cut, but it can't be predicted without the points that were removed.
+
+use crate::{distance, round, HeatmapOptions, InputDiagnostics, Track};
+
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Generator state for an activity, from the raw points of all its segments
+pub(crate) fn trim_seed(segments: &[Track], options: &HeatmapOptions) -> u64 {
+    let mut state = options.trim_seed;
+    for [lat, lon] in segments.iter().flat_map(|segment| &segment.coordinates) {
+        state ^= lat.to_bits();
+        split_mix(&mut state);
+        state ^= lon.to_bits();
+        split_mix(&mut state);
+    }
+    state
+}
+
+// Trim the cleaned pieces of one activity, in order, as a whole
+pub(crate) fn trim_ends(
+    pieces: Vec<Track>,
+    mut seed: u64,
+    options: &HeatmapOptions,
+    diagnostics: &mut InputDiagnostics,
+) -> Vec<Track> {
+    if options.trim_m == 0.0 && options.trim_jitter_m == 0.0 {
+        return pieces;
+    }
+
+    let mut jittered = || {
+        let fraction = (split_mix(&mut seed) >> 11) as f64 / (1u64 << 53) as f64;
+        options.trim_m + fraction * options.trim_jitter_m
+    };
+    let (start, end) = (jittered(), jittered());
+
+    let pieces = trim_front(pieces, start, options, diagnostics);
+    let reversed = pieces.into_iter().rev().map(reverse).collect();
+    trim_front(reversed, end, options, diagnostics).into_iter().rev().map(reverse).collect()
+}
+
+// Drop the first `length` meters, walking through the pieces in order
+fn trim_front(pieces: Vec<Track>, length: f64, options: &HeatmapOptions, diagnostics: &mut InputDiagnostics) -> Vec<Track> {
+    let mut remaining = length;
+    let mut pieces = pieces.into_iter();
+    let mut kept = Vec::new();
+
+    for piece in pieces.by_ref() {
+        let mut walked = 0.0;
+        let cut = (1..piece.len()).find_map(|i| {
+            let step = distance(piece.coordinates[i - 1], piece.coordinates[i]);
+            if walked + step > remaining {
+                Some((i, (remaining - walked) / step))
+            } else {
+                walked += step;
+                None
+            }
+        });
+
+        match cut {
+            Some((i, t)) => {
+                diagnostics.trimmed_points += i - 1;
+                kept.push(cut_before(&piece, i, t, options));
+                break;
+            }
+            None => {
+                diagnostics.trimmed_points += piece.len();
+                remaining -= walked;
+            }
+        }
+    }
+
+    kept.extend(pieces);
+    kept
+}
+
+// The piece from a fraction t of the way between points i - 1 and i onwards
+fn cut_before(piece: &Track, i: usize, t: f64, options: &HeatmapOptions) -> Track {
+    let mut kept = piece.slice(i - 1..piece.len());
+    let lerp = |from: f64, to: f64| from + (to - from) * t;
+
+    let [from, to] = [piece.coordinates[i - 1], piece.coordinates[i]];
+    let precision = options.coordinate_precision;
+    kept.coordinates[0] = [round(lerp(from[0], to[0]), precision), round(lerp(from[1], to[1]), precision)];
+    if let Some(elevations) = &mut kept.elevations {
+        elevations[0] = lerp(elevations[0], elevations[1]);
+    }
+    if let Some(times) = &mut kept.times {
+        times[0] = lerp(times[0], times[1]);
+    }
+    kept
+}
+
+fn reverse(mut track: Track) -> Track {
+    track.coordinates.reverse();
+    if let Some(elevations) = &mut track.elevations {
+        elevations.reverse();
+    }
+    if let Some(times) = &mut track.times {
+        times.reverse();
+    }
+    track
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_gpx::gpx;
+    use crate::{HeatmapBuilder, HeatmapOptions};
+
+    // One GPX activity heading north ~10 m per point, split into two segments
+    // with a short pause between them
+    fn two_segment_gpx() -> Vec<u8> {
+        let points = |range: std::ops::Range<usize>| range.map(|i| (i, [47.6 + i as f64 * 0.00009, -122.3])).collect();
+        gpx(&[points(0..200), points(205..400)])
+    }
+
+    #[test]
+    fn trims_activity_ends_not_segment_boundaries() {
+        let options = HeatmapOptions {
+            trim_m: 100.0,
+            ..HeatmapOptions::default()
+        };
+        let mut builder = HeatmapBuilder::new(options).unwrap();
+        let diagnostics = builder.add_file(&two_segment_gpx()).clone();
+
+        // Points are ~10 m apart: about 10 go at each end of the activity, none
+        // where the segments meet
+        assert!((18..=22).contains(&diagnostics.trimmed_points), "trimmed {}", diagnostics.trimmed_points);
+        assert_eq!(builder.track_count(), 2);
+        assert_eq!(builder.endpoints.len(), 2);
+    }
+
+    #[test]
+    fn seed_covers_every_segment() {
+        let options = HeatmapOptions {
+            trim_jitter_m: 500.0,
+            ..HeatmapOptions::default()
+        };
+        let start = |gpx: &[u8]| {
+            let mut builder = HeatmapBuilder::new(options.clone()).unwrap();
+            builder.add_file(gpx);
+            builder.finish().tracks[0].coordinates[0]
+        };
+        let gpx = two_segment_gpx();
+        assert_eq!(start(&gpx), start(&gpx));
+
+        // Moving a point of the second segment changes the cut at the start
+        let text = String::from_utf8(gpx.clone()).unwrap();
+        let moved = text.replacen(r#"lat="47.627000""#, r#"lat="47.627010""#, 1);
+        assert_ne!(moved, text);
+        assert_ne!(start(moved.as_bytes()), start(&gpx));
+    }
+}
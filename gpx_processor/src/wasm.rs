@@ -0,0 +1,234 @@
+// WebAssembly bindings, enabled by the `wasm` feature. These are thin wrappers
+// around the native API that convert to and from JS values.
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    CancellationToken, HeatmapBuilder, HeatmapError, HeatmapInput, HeatmapOptions, Result, RwgpsDocument,
+    StravaActivity,
+};
+
+// Add a console log function for debugging
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+// Convert a result for JS, surfacing serialization failures as errors instead of panicking
+fn to_js<T: Serialize + ?Sized>(value: &T) -> Result<JsValue> {
+    serde_wasm_bindgen::to_value(value).map_err(|e| HeatmapError::Serialization(e.to_string()))
+}
+
+fn from_js<T: serde::de::DeserializeOwned>(value: JsValue) -> Result<T> {
+    serde_wasm_bindgen::from_value(value).map_err(|e| HeatmapError::Parse(e.to_string()))
+}
+
+// For exports that may also rethrow a JS exception unchanged
+fn js_error(error: HeatmapError) -> JsValue {
+    JsError::from(error).into()
+}
+
+// Options are an optional plain object; undefined or null means all defaults
+fn options_from_js(options: JsValue) -> Result<HeatmapOptions> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(HeatmapOptions::default());
+    }
+    serde_wasm_bindgen::from_value(options).map_err(|e| HeatmapError::InvalidOptions(e.to_string()))
+}
+
+// Owner IDs are an optional array with one entry per input; entries that
+// aren't strings, or a missing array, leave those inputs without an owner
+fn owners_from_js(owners: Option<js_sys::Array>, count: usize) -> Result<Vec<Option<String>>> {
+    let Some(owners) = owners else {
+        return Ok(vec![None; count]);
+    };
+    if owners.length() as usize != count {
+        return Err(HeatmapError::InvalidOptions("owners must have one entry per input".to_string()));
+    }
+    Ok(owners.iter().map(|owner| owner.as_string()).collect())
+}
+
+// Wasm-bindgen export for polyline decoding
+#[wasm_bindgen]
+pub fn decode_polyline_string(encoded: &str) -> std::result::Result<JsValue, JsError> {
+    let coords = crate::decode_polyline(encoded);
+    Ok(to_js(&coords)?)
+}
+
+// `on_progress` is called with a Progress object before each phase of each
+// file; returning false from it cancels the run, which then throws "cancelled".
+// The run is synchronous, so the callback is the only way to stop it.
+// `owners` optionally gives each file's owner ID for `min_owners`.
+#[wasm_bindgen]
+pub fn process_gpx_files(
+    files: js_sys::Array,
+    options: JsValue,
+    on_progress: Option<js_sys::Function>,
+    owners: Option<js_sys::Array>,
+) -> std::result::Result<JsValue, JsValue> {
+    let options = options_from_js(options).map_err(js_error)?;
+    let buffers: Vec<Vec<u8>> = files
+        .iter()
+        .map(|file_bytes| js_sys::Uint8Array::new(&file_bytes).to_vec())
+        .collect();
+    let owners = owners_from_js(owners, buffers.len()).map_err(js_error)?;
+    let inputs: Vec<HeatmapInput> = buffers
+        .iter()
+        .zip(&owners)
+        .map(|(bytes, owner)| HeatmapInput::file(bytes).with_owner(owner.as_deref()))
+        .collect();
+
+    let Some(on_progress) = on_progress else {
+        return crate::process_inputs(&inputs, &options).and_then(|result| to_js(&result)).map_err(js_error);
+    };
+
+    // An exception thrown by the callback stops the run and is rethrown as-is
+    let mut callback_error = None;
+    let result = crate::process_inputs_with_progress(&inputs, &options, &CancellationToken::new(), |progress| {
+        let keep_going = to_js(progress)
+            .map_err(js_error)
+            .and_then(|progress| on_progress.call1(&JsValue::NULL, &progress));
+        match keep_going {
+            Ok(value) => value != JsValue::FALSE,
+            Err(error) => {
+                callback_error = Some(error);
+                false
+            }
+        }
+    });
+    if let Some(error) = callback_error {
+        return Err(error);
+    }
+    result.and_then(|result| to_js(&result)).map_err(js_error)
+}
+
+// Add a function to process polylines from strings, with optional owner IDs
+// as for process_gpx_files
+#[wasm_bindgen]
+pub fn process_polylines(
+    polylines: js_sys::Array,
+    options: JsValue,
+    owners: Option<js_sys::Array>,
+) -> std::result::Result<JsValue, JsError> {
+    let options = options_from_js(options)?;
+
+    // Non-string entries are kept so they show up in the diagnostics
+    let polylines: Vec<Option<String>> = polylines.iter().map(|p| p.as_string()).collect();
+    let owners = owners_from_js(owners, polylines.len())?;
+
+    let inputs = polylines.iter().map(Option::as_deref).zip(owners.iter().map(Option::as_deref));
+    let result = crate::process_polyline_inputs(inputs, &options)?;
+    Ok(to_js(&result)?)
+}
+
+// Process Strava SummaryActivity objects; see `crate::process_strava`
+#[wasm_bindgen]
+pub fn process_strava_activities(activities: JsValue, options: JsValue) -> std::result::Result<JsValue, JsError> {
+    let options = options_from_js(options)?;
+    let activities: Vec<StravaActivity> = from_js(activities)?;
+
+    let result = crate::process_strava(activities, &options)?;
+    Ok(to_js(&result)?)
+}
+
+// Process RideWithGPS trip or route JSON; see `crate::process_rwgps`
+#[wasm_bindgen]
+pub fn process_rwgps_trips(trips: JsValue, options: JsValue) -> std::result::Result<JsValue, JsError> {
+    let options = options_from_js(options)?;
+    let trips: Vec<RwgpsDocument> = from_js(trips)?;
+
+    let result = crate::process_rwgps(trips, &options)?;
+    Ok(to_js(&result)?)
+}
+
+// Stateful builder for adding activities one at a time:
+//
+//   const builder = new HeatmapBuilder(options);
+//   builder.add_file(bytes);
+//   const partial = builder.snapshot();
+//   const result = builder.finish(); // the builder can't be used afterwards
+//
+// save() returns the builder state as a Uint8Array that HeatmapBuilder.load(bytes)
+// turns back into a builder, e.g. to keep an aggregated archive in IndexedDB.
+// Each add_* call returns the diagnostics entry for that input.
+#[wasm_bindgen(js_name = HeatmapBuilder)]
+pub struct WasmHeatmapBuilder {
+    inner: HeatmapBuilder,
+}
+
+#[wasm_bindgen(js_class = HeatmapBuilder)]
+impl WasmHeatmapBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new(options: JsValue) -> std::result::Result<WasmHeatmapBuilder, JsError> {
+        let inner = HeatmapBuilder::new(options_from_js(options)?)?;
+        Ok(WasmHeatmapBuilder { inner })
+    }
+
+    // Restore a builder from bytes returned by save()
+    pub fn load(bytes: &[u8]) -> std::result::Result<WasmHeatmapBuilder, JsError> {
+        let inner = HeatmapBuilder::load(bytes)?;
+        Ok(WasmHeatmapBuilder { inner })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn input_count(&self) -> usize {
+        self.inner.input_count()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn track_count(&self) -> usize {
+        self.inner.track_count()
+    }
+
+    // Owner ID (e.g. a club member) for the inputs added from now on, for
+    // `min_owners`; undefined goes back to the shared unknown owner
+    pub fn set_owner(&mut self, owner: Option<String>) {
+        self.inner.set_owner(owner.as_deref());
+    }
+
+    // Add a GPX or FIT file
+    pub fn add_file(&mut self, bytes: &[u8]) -> std::result::Result<JsValue, JsError> {
+        Ok(to_js(self.inner.add_file(bytes))?)
+    }
+
+    // Add an encoded polyline or a JSON array of [lat, lon] pairs
+    pub fn add_polyline(&mut self, polyline: &str) -> std::result::Result<JsValue, JsError> {
+        Ok(to_js(self.inner.add_polyline(polyline))?)
+    }
+
+    pub fn add_strava_activity(&mut self, activity: JsValue) -> std::result::Result<JsValue, JsError> {
+        let activity: StravaActivity = from_js(activity)?;
+        Ok(to_js(self.inner.add_strava_activity(activity))?)
+    }
+
+    pub fn add_rwgps_trip(&mut self, trip: JsValue) -> std::result::Result<JsValue, JsError> {
+        let trip: RwgpsDocument = from_js(trip)?;
+        Ok(to_js(self.inner.add_rwgps_trip(trip))?)
+    }
+
+    // The current HeatmapResult; the builder keeps accepting input afterwards
+    pub fn snapshot(&self) -> std::result::Result<JsValue, JsError> {
+        Ok(to_js(&self.inner.snapshot())?)
+    }
+
+    // The segment counts as a merged road network ({ nodes, edges })
+    pub fn network(&self) -> std::result::Result<JsValue, JsError> {
+        Ok(to_js(&self.inner.network())?)
+    }
+
+    // Suggested privacy zones from clustered activity endpoints
+    // ([{ zone: { circle: { center, radius_m } }, count }])
+    pub fn suggest_privacy_zones(&self, radius_m: f64, min_endpoints: usize) -> std::result::Result<JsValue, JsError> {
+        Ok(to_js(&self.inner.suggest_privacy_zones(radius_m, min_endpoints)?)?)
+    }
+
+    pub fn save(&self) -> js_sys::Uint8Array {
+        js_sys::Uint8Array::from(self.inner.save().as_slice())
+    }
+
+    pub fn finish(self) -> std::result::Result<JsValue, JsError> {
+        Ok(to_js(&self.inner.finish())?)
+    }
+}